@@ -0,0 +1,120 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+/// What a user may do with a file. Every level includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Read the content, metadata and history.
+    Read,
+    /// Store new versions, restore, rename, delete and change metadata.
+    Write,
+    /// Grant and revoke read and write access.
+    Share,
+    /// Grant and revoke any access and hand the file to another owner.
+    Admin,
+}
+
+/// Who an [`AclEntry`] applies to.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Principal {
+    /// A single user, by beeid.
+    User(String),
+    /// Every member of a group, see [`Database::add_to_group`].
+    ///
+    /// [`Database::add_to_group`]: crate::database::Database::add_to_group
+    Group(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AclEntry {
+    pub principal: Principal,
+    pub permission: Permission,
+}
+
+/// Who may do what with a file. The owner may do everything.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Acl {
+    /// Beeid of the user owning the file.
+    pub owner: String,
+    pub entries: Vec<AclEntry>,
+}
+
+/// A named set of users that can be granted access together. Only its owner changes it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Group {
+    pub owner: String,
+    pub members: BTreeSet<String>,
+}
+
+impl Acl {
+    pub fn new(owner: String) -> Self {
+        Acl { owner, entries: Vec::new() }
+    }
+
+    /// The highest permission `beeid` has, directly or through one of its groups. Nobody signed
+    /// in has none.
+    pub fn permission_of(&self, beeid: Option<&str>, groups: &BTreeMap<String, Group>) -> Option<Permission> {
+        let beeid = beeid?;
+        if beeid == self.owner {
+            return Some(Permission::Admin);
+        }
+        self.entries
+            .iter()
+            .filter(|entry| match &entry.principal {
+                Principal::User(user) => user == beeid,
+                Principal::Group(group) => groups.get(group).is_some_and(|group| group.members.contains(beeid)),
+            })
+            .map(|entry| entry.permission)
+            .max()
+    }
+
+    pub fn allows(&self, beeid: Option<&str>, groups: &BTreeMap<String, Group>, permission: Permission) -> bool {
+        self.permission_of(beeid, groups).is_some_and(|granted| granted >= permission)
+    }
+
+    /// Gives `principal` exactly `permission`, replacing what it had before.
+    pub fn grant(&mut self, principal: Principal, permission: Permission) {
+        self.revoke(&principal);
+        self.entries.push(AclEntry { principal, permission });
+    }
+
+    /// What `principal` itself was granted, not counting its groups.
+    pub fn granted(&self, principal: &Principal) -> Option<Permission> {
+        self.entries.iter().find(|entry| entry.principal == *principal).map(|entry| entry.permission)
+    }
+
+    /// Removes the entry of `principal`, returning the permission it had.
+    pub fn revoke(&mut self, principal: &Principal) -> Option<Permission> {
+        let index = self.entries.iter().position(|entry| entry.principal == *principal)?;
+        Some(self.entries.remove(index).permission)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permissions_come_from_users_and_groups() {
+        let mut groups = BTreeMap::new();
+        let members = ["seigr_bee2".to_string()].into_iter().collect();
+        groups.insert("team".to_string(), Group { owner: "seigr_bee1".to_string(), members });
+
+        let mut acl = Acl::new("seigr_bee1".to_string());
+        acl.grant(Principal::Group("team".to_string()), Permission::Read);
+        acl.grant(Principal::User("seigr_bee3".to_string()), Permission::Share);
+
+        assert!(acl.allows(Some("seigr_bee1"), &groups, Permission::Admin));
+        assert!(acl.allows(Some("seigr_bee2"), &groups, Permission::Read));
+        assert!(!acl.allows(Some("seigr_bee2"), &groups, Permission::Write));
+        assert!(acl.allows(Some("seigr_bee3"), &groups, Permission::Write));
+        assert!(!acl.allows(Some("seigr_bee4"), &groups, Permission::Read));
+        assert!(!acl.allows(None, &groups, Permission::Read));
+
+        assert_eq!(acl.revoke(&Principal::User("seigr_bee3".to_string())), Some(Permission::Share));
+        assert_eq!(acl.permission_of(Some("seigr_bee3"), &groups), None);
+    }
+}
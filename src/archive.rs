@@ -0,0 +1,341 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read, Write};
+
+use blake2::{Blake2b, Digest};
+use generic_array::typenum::U64;
+use serde::{Deserialize, Serialize};
+
+use crate::catalog::FileVersion;
+use crate::chunker::MAX_CHUNK_SIZE;
+use crate::codec::MAX_ENCODING_OVERHEAD;
+use crate::database::{DatabaseError, Frame};
+
+/// First bytes of every archive, followed by [`ARCHIVE_VERSION`].
+pub const ARCHIVE_MAGIC: &[u8; 8] = b"SEIGRHIV";
+pub const ARCHIVE_VERSION: u32 = 1;
+
+const MANIFEST_RECORD: u8 = b'M';
+const CUBE_RECORD: u8 = b'C';
+const OBJECT_RECORD: u8 = b'O';
+const END_RECORD: u8 = b'E';
+
+// No record is read into memory without a bound. The manifest grows with the number of files and
+// objects, a cube holds at most `FRAMES_PER_CUBE` frames of `CELLS_PER_FRAME` beecells each.
+const MAX_MANIFEST_LENGTH: u64 = 256 * 1024 * 1024;
+const MAX_CUBE_LENGTH: u64 = 16 * 1024 * 1024;
+// The address with its length, and one beecell or parity shard as stored
+const MAX_OBJECT_LENGTH: u64 = (2 + u16::MAX as usize + MAX_CHUNK_SIZE + MAX_ENCODING_OVERHEAD) as u64;
+// A hex Blake2b checksum
+const MAX_END_LENGTH: u64 = 128;
+
+/// What [`Database::export_archive`] wrote or [`Database::import_archive`] read.
+///
+/// [`Database::export_archive`]: crate::database::Database::export_archive
+/// [`Database::import_archive`]: crate::database::Database::import_archive
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ArchiveReport {
+    pub files: usize,
+    pub versions: usize,
+    pub cubes: usize,
+    /// Beecells and parity shards.
+    pub objects: usize,
+    /// Objects the hive already had, so they weren't written again. Always 0 for an export.
+    pub deduplicated: usize,
+    /// Size of the whole archive.
+    pub bytes: u64,
+}
+
+// Describes everything in the archive, so an import knows what to expect before reading any of it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ArchiveManifest {
+    pub(crate) created: u64,
+    pub(crate) files: Vec<ArchivedFile>,
+    pub(crate) cubes: Vec<String>,
+    pub(crate) objects: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ArchivedFile {
+    pub(crate) filename: String,
+    // Oldest first, like in the catalog
+    pub(crate) versions: Vec<FileVersion>,
+    #[serde(default)]
+    pub(crate) metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub(crate) tags: BTreeSet<String>,
+}
+
+// A cube with its frames, which the hive keeps in separate manifests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ArchivedCube {
+    pub(crate) id: String,
+    pub(crate) root: String,
+    pub(crate) frames: Vec<Frame>,
+}
+
+pub(crate) enum Record {
+    Cube(ArchivedCube),
+    Object { address: String, data: Vec<u8> },
+}
+
+fn invalid(message: impl Into<String>) -> DatabaseError {
+    DatabaseError::InvalidArchive(message.into())
+}
+
+/// Writes a `.hive` archive.
+///
+/// ```text
+/// "SEIGRHIV" version:u32                        header, integers are big-endian
+/// 'M' length:u64 JSON                           the manifest, always the first record
+/// 'C' length:u64 JSON                           a cube with its frames, one per cube
+/// 'O' length:u64 address_length:u16 address data  a stored beecell or parity shard, one per object
+/// 'E' length:u64 checksum                       hex Blake2b of everything before this record
+/// ```
+pub(crate) struct ArchiveWriter<W: Write> {
+    inner: W,
+    hasher: Blake2b<U64>,
+    written: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub(crate) fn new(inner: W) -> io::Result<Self> {
+        let mut writer = ArchiveWriter {
+            inner,
+            hasher: Blake2b::default(),
+            written: 0,
+        };
+        writer.write_bytes(ARCHIVE_MAGIC)?;
+        writer.write_bytes(&ARCHIVE_VERSION.to_be_bytes())?;
+        Ok(writer)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_all(bytes)?;
+        self.hasher.update(bytes);
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn write_record(&mut self, kind: u8, parts: &[&[u8]]) -> io::Result<()> {
+        let length: usize = parts.iter().map(|part| part.len()).sum();
+        self.write_bytes(&[kind])?;
+        self.write_bytes(&(length as u64).to_be_bytes())?;
+        for part in parts {
+            self.write_bytes(part)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_manifest(&mut self, manifest: &ArchiveManifest) -> io::Result<()> {
+        self.write_record(MANIFEST_RECORD, &[&serde_json::to_vec(manifest)?])
+    }
+
+    pub(crate) fn write_cube(&mut self, cube: &ArchivedCube) -> io::Result<()> {
+        self.write_record(CUBE_RECORD, &[&serde_json::to_vec(cube)?])
+    }
+
+    pub(crate) fn write_object(&mut self, address: &str, data: &[u8]) -> io::Result<()> {
+        let address_length = u16::try_from(address.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Object address too long"))?;
+        self.write_record(OBJECT_RECORD, &[&address_length.to_be_bytes(), address.as_bytes(), data])
+    }
+
+    /// Writes the checksum, returning the size of the whole archive.
+    pub(crate) fn finish(mut self) -> io::Result<u64> {
+        let checksum = hex::encode(self.hasher.clone().finalize());
+        self.write_record(END_RECORD, &[checksum.as_bytes()])?;
+        self.inner.flush()?;
+        Ok(self.written)
+    }
+}
+
+/// Reads a `.hive` archive record by record, see [`ArchiveWriter`] for the layout.
+pub(crate) struct ArchiveReader<R: Read> {
+    inner: R,
+    hasher: Blake2b<U64>,
+    read: u64,
+    finished: bool,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    /// Checks the header and reads the manifest.
+    pub(crate) fn open(inner: R) -> Result<(Self, ArchiveManifest), DatabaseError> {
+        let mut reader = ArchiveReader {
+            inner,
+            hasher: Blake2b::default(),
+            read: 0,
+            finished: false,
+        };
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        reader.hasher.update(header);
+        if header[..8] != ARCHIVE_MAGIC[..] {
+            return Err(invalid("not a hive archive"));
+        }
+        let version = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        if version != ARCHIVE_VERSION {
+            return Err(invalid(format!("unsupported format version {}", version)));
+        }
+
+        let (kind, payload) = reader.read_record()?;
+        if kind != MANIFEST_RECORD {
+            return Err(invalid("the manifest is missing"));
+        }
+        let manifest = serde_json::from_slice(&payload).map_err(|error| invalid(format!("unreadable manifest: {}", error)))?;
+        Ok((reader, manifest))
+    }
+
+    /// Bytes read so far.
+    pub(crate) fn position(&self) -> u64 {
+        self.read
+    }
+
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), DatabaseError> {
+        match self.inner.read_exact(buffer) {
+            Ok(()) => {
+                self.read += buffer.len() as u64;
+                Ok(())
+            }
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Err(invalid("the archive is truncated")),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    // Reads the kind and payload of the next record. Only the header is hashed, so that the
+    // checksum can be taken before the end record is
+    fn read_record_header(&mut self) -> Result<(u8, u64), DatabaseError> {
+        let mut header = [0u8; 9];
+        self.read_exact(&mut header)?;
+        let mut length = [0u8; 8];
+        length.copy_from_slice(&header[1..]);
+        let (kind, length) = (header[0], u64::from_be_bytes(length));
+        let max_length = match kind {
+            MANIFEST_RECORD => MAX_MANIFEST_LENGTH,
+            CUBE_RECORD => MAX_CUBE_LENGTH,
+            OBJECT_RECORD => MAX_OBJECT_LENGTH,
+            END_RECORD => MAX_END_LENGTH,
+            _ => return Err(invalid(format!("unknown record kind {:?}", kind as char))),
+        };
+        if length > max_length {
+            return Err(invalid(format!("{:?} record of {} bytes is too large", kind as char, length)));
+        }
+        if kind != END_RECORD {
+            self.hasher.update(header);
+        }
+        Ok((kind, length))
+    }
+
+    fn read_payload(&mut self, length: u64) -> Result<Vec<u8>, DatabaseError> {
+        // The length isn't trusted for an allocation, the payload has to actually be there
+        let mut payload = Vec::new();
+        (&mut self.inner).take(length).read_to_end(&mut payload)?;
+        if payload.len() as u64 != length {
+            return Err(invalid("the archive is truncated"));
+        }
+        self.read += length;
+        Ok(payload)
+    }
+
+    fn read_record(&mut self) -> Result<(u8, Vec<u8>), DatabaseError> {
+        let (kind, length) = self.read_record_header()?;
+        let payload = self.read_payload(length)?;
+        self.hasher.update(&payload);
+        Ok((kind, payload))
+    }
+
+    /// The next cube or object, `None` once the checksum has been read and found to match.
+    pub(crate) fn next_record(&mut self) -> Result<Option<Record>, DatabaseError> {
+        if self.finished {
+            return Ok(None);
+        }
+        let checksum = hex::encode(self.hasher.clone().finalize());
+        let (kind, length) = self.read_record_header()?;
+        if kind == END_RECORD {
+            if self.read_payload(length)? != checksum.as_bytes() {
+                return Err(invalid("checksum mismatch"));
+            }
+            if self.inner.read(&mut [0u8; 1])? != 0 {
+                return Err(invalid("unexpected data after the checksum"));
+            }
+            self.finished = true;
+            return Ok(None);
+        }
+
+        let payload = self.read_payload(length)?;
+        self.hasher.update(&payload);
+        match kind {
+            CUBE_RECORD => {
+                let cube = serde_json::from_slice(&payload).map_err(|error| invalid(format!("unreadable cube: {}", error)))?;
+                Ok(Some(Record::Cube(cube)))
+            }
+            OBJECT_RECORD => {
+                let (address_length, rest) = payload.split_at_checked(2).ok_or_else(|| invalid("object record too short"))?;
+                let address_length = u16::from_be_bytes([address_length[0], address_length[1]]) as usize;
+                let (address, data) = rest.split_at_checked(address_length).ok_or_else(|| invalid("object record too short"))?;
+                let address = String::from_utf8(address.to_vec()).map_err(|_| invalid("object address is not UTF-8"))?;
+                Ok(Some(Record::Object { address, data: data.to_vec() }))
+            }
+            _ => Err(invalid(format!("unknown record kind {:?}", kind as char))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> ArchiveManifest {
+        ArchiveManifest {
+            created: 1,
+            files: Vec::new(),
+            cubes: Vec::new(),
+            objects: vec!["ab".to_string()],
+        }
+    }
+
+    fn archive() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut writer = ArchiveWriter::new(&mut bytes).unwrap();
+        writer.write_manifest(&manifest()).unwrap();
+        writer.write_object("ab", b"payload").unwrap();
+        let length = writer.finish().unwrap();
+        assert_eq!(length, bytes.len() as u64);
+        bytes
+    }
+
+    fn read_all(bytes: &[u8]) -> Result<Vec<(String, Vec<u8>)>, DatabaseError> {
+        let (mut reader, manifest) = ArchiveReader::open(bytes)?;
+        assert_eq!(manifest.objects, vec!["ab".to_string()]);
+        let mut objects = Vec::new();
+        while let Some(record) = reader.next_record()? {
+            if let Record::Object { address, data } = record {
+                objects.push((address, data));
+            }
+        }
+        Ok(objects)
+    }
+
+    #[test]
+    fn test_records_round_trip_and_damage_is_detected() {
+        let bytes = archive();
+        assert_eq!(read_all(&bytes).unwrap(), vec![("ab".to_string(), b"payload".to_vec())]);
+
+        let mut flipped = bytes.clone();
+        let position = flipped.windows(7).position(|window| window == b"payload").unwrap();
+        flipped[position] ^= 1;
+        assert!(matches!(read_all(&flipped), Err(DatabaseError::InvalidArchive(_))));
+
+        assert!(matches!(read_all(&bytes[..bytes.len() - 10]), Err(DatabaseError::InvalidArchive(_))));
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(matches!(read_all(&trailing), Err(DatabaseError::InvalidArchive(_))));
+        assert!(matches!(ArchiveReader::open(&b"not an archive at all"[..]), Err(DatabaseError::InvalidArchive(_))));
+
+        // A record claiming more than it could ever hold is refused before it is read
+        let mut oversized = bytes.clone();
+        // The object follows the header and the manifest record
+        let position = 12 + 9 + serde_json::to_vec(&manifest()).unwrap().len();
+        assert_eq!(oversized[position], OBJECT_RECORD);
+        oversized[position + 1..position + 9].copy_from_slice(&(MAX_OBJECT_LENGTH + 1).to_be_bytes());
+        assert!(matches!(read_all(&oversized), Err(DatabaseError::InvalidArchive(message)) if message.contains("too large")));
+    }
+}
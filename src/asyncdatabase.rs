@@ -0,0 +1,329 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{self, JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+use crate::database::{Database, DatabaseError, File, GcReport, ScrubReport};
+use crate::listing::{FileListing, ListOptions};
+use crate::stream::STREAM_BUFFER_SIZE;
+
+/// How many operations an [`AsyncDatabase`] runs at once unless told otherwise.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 8;
+
+// Buffers a stream keeps queued between the async side and the blocking side. A reader or
+// writer that is faster than the hive waits once the queue is full.
+const STREAM_QUEUE_LENGTH: usize = 4;
+
+/// Async front end to a [`Database`], so that the TUI or a network front end stays responsive
+/// during large stores.
+///
+/// Every operation runs on tokio's blocking pool, where the hashing, compression and IO happen,
+/// and the returned future only waits for it. At most `max_in_flight` operations run at once,
+/// the others wait for their turn, see [`AsyncDatabase::available_permits`].
+///
+/// Operations can be cancelled with the token given to [`AsyncDatabase::with_cancellation`].
+/// Waiting operations and streams stop as soon as it is cancelled. An operation already on the
+/// blocking pool runs to the end, and like every change to the hive it is applied completely or
+/// not at all.
+///
+/// ```ignore
+/// let database = AsyncDatabase::new(database);
+/// let token = CancellationToken::new();
+/// database.with_cancellation(token.clone()).store_reader("movie.bin".to_string(), file).await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct AsyncDatabase {
+    database: Arc<Database>,
+    permits: Arc<Semaphore>,
+    cancel: CancellationToken,
+}
+
+impl AsyncDatabase {
+    pub fn new(database: Arc<Database>) -> Self {
+        Self::with_max_in_flight(database, DEFAULT_MAX_IN_FLIGHT)
+    }
+
+    pub fn with_max_in_flight(database: Arc<Database>, max_in_flight: usize) -> Self {
+        AsyncDatabase {
+            database,
+            permits: Arc::new(Semaphore::new(max_in_flight.max(1))),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// The same database, with operations that stop when `token` is cancelled. The limit on
+    /// operations in flight is shared with `self`.
+    pub fn with_cancellation(&self, token: CancellationToken) -> Self {
+        AsyncDatabase {
+            cancel: token,
+            ..self.clone()
+        }
+    }
+
+    /// The database behind this one, for calls that don't need to be async.
+    pub fn database(&self) -> &Arc<Database> {
+        &self.database
+    }
+
+    /// How many more operations can start right away. At zero, new operations wait until one
+    /// in flight finishes.
+    pub fn available_permits(&self) -> usize {
+        self.permits.available_permits()
+    }
+
+    // Waits for a turn, unless cancelled first
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit, DatabaseError> {
+        tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => Err(DatabaseError::Cancelled),
+            permit = self.permits.clone().acquire_owned() => permit.map_err(|_| DatabaseError::Other("The database is closed".to_string())),
+        }
+    }
+
+    /// Runs `op` on the blocking pool once it is its turn, for anything without an async
+    /// version of its own.
+    pub async fn run<T, F>(&self, op: F) -> Result<T, DatabaseError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> Result<T, DatabaseError> + Send + 'static,
+    {
+        let permit = self.acquire().await?;
+        let database = self.database.clone();
+        join(task::spawn_blocking(move || {
+            let _permit = permit;
+            op(&database)
+        }))
+        .await
+    }
+
+    pub async fn store_file(&self, filename: String, data: Vec<u8>) -> Result<(), DatabaseError> {
+        self.run(move |database| database.store_file(filename, data)).await
+    }
+
+    /// Stores everything `reader` yields under `filename`. The content is read here and
+    /// stored on the blocking pool as it arrives, a few buffers at a time.
+    ///
+    /// Nothing is stored if the reader fails or the store is cancelled before the end, and the
+    /// beecells written until then are deleted again.
+    pub async fn store_reader<R: AsyncRead + Unpin>(&self, filename: String, mut reader: R) -> Result<(), DatabaseError> {
+        let permit = self.acquire().await?;
+        let (sender, receiver) = mpsc::channel(STREAM_QUEUE_LENGTH);
+        let database = self.database.clone();
+        let store = task::spawn_blocking(move || {
+            let _permit = permit;
+            database.store_reader(filename, ChannelReader::new(receiver))
+        });
+
+        let feed = async {
+            let mut buffer = vec![0u8; STREAM_BUFFER_SIZE];
+            loop {
+                let count = reader.read(&mut buffer).await?;
+                if count == 0 {
+                    return Ok::<(), io::Error>(());
+                }
+                // The store gave up, its own error is the one reported
+                if sender.send(Ok(buffer[..count].to_vec())).await.is_err() {
+                    return Ok(());
+                }
+            }
+        };
+        let fed: Result<(), DatabaseError> = tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => Err(DatabaseError::Cancelled),
+            fed = feed => fed.map_err(DatabaseError::from),
+        };
+
+        if let Err(error) = fed {
+            // The end of the stream would store what was sent so far, so the store is failed instead
+            let _ = sender.send(Err(io::Error::other(error.to_string()))).await;
+            drop(sender);
+            let _ = join(store).await;
+            return Err(error);
+        }
+        drop(sender);
+        join(store).await
+    }
+
+    pub async fn retrieve_file(&self, filename: &str) -> Result<Vec<u8>, DatabaseError> {
+        let filename = filename.to_string();
+        self.run(move |database| database.retrieve_file(&filename)).await
+    }
+
+    /// Streams a stored file into `writer`, returning the number of bytes written. The file is
+    /// read on the blocking pool a few buffers ahead of the writer.
+    pub async fn retrieve_to_writer<W: AsyncWrite + Unpin>(&self, filename: &str, mut writer: W) -> Result<u64, DatabaseError> {
+        let permit = self.acquire().await?;
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(STREAM_QUEUE_LENGTH);
+        let database = self.database.clone();
+        let filename = filename.to_string();
+        let read = task::spawn_blocking(move || -> Result<(), DatabaseError> {
+            let _permit = permit;
+            let mut reader = database.open_reader(&filename)?;
+            loop {
+                let mut buffer = vec![0u8; STREAM_BUFFER_SIZE];
+                let count = reader.read(&mut buffer)?;
+                if count == 0 {
+                    return Ok(());
+                }
+                buffer.truncate(count);
+                // Nobody is receiving anymore once the retrieval is cancelled
+                if sender.blocking_send(buffer).is_err() {
+                    return Ok(());
+                }
+            }
+        });
+
+        let write = async {
+            let mut written = 0;
+            while let Some(buffer) = receiver.recv().await {
+                writer.write_all(&buffer).await?;
+                written += buffer.len() as u64;
+            }
+            writer.flush().await?;
+            Ok::<u64, io::Error>(written)
+        };
+        let written = tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => Err(DatabaseError::Cancelled),
+            written = write => written.map_err(DatabaseError::from),
+        };
+        drop(receiver);
+        // A file that couldn't be read to the end ends the stream early, that is the error to report
+        join(read).await?;
+        written
+    }
+
+    pub async fn stat(&self, filename: &str) -> Result<File, DatabaseError> {
+        let filename = filename.to_string();
+        self.run(move |database| database.stat(&filename)).await
+    }
+
+    pub async fn list_files(&self, options: ListOptions) -> Result<FileListing, DatabaseError> {
+        self.run(move |database| database.list_files(&options)).await
+    }
+
+    pub async fn query(&self, query: &str) -> Result<Vec<File>, DatabaseError> {
+        let query = query.to_string();
+        self.run(move |database| database.query(&query)).await
+    }
+
+    pub async fn delete_file(&self, filename: String) -> Result<(), DatabaseError> {
+        self.run(move |database| database.delete_file(filename)).await
+    }
+
+    pub async fn rename_file(&self, from: String, to: String) -> Result<(), DatabaseError> {
+        self.run(move |database| database.rename_file(from, to)).await
+    }
+
+    pub async fn scrub(&self) -> Result<ScrubReport, DatabaseError> {
+        self.run(|database| database.scrub()).await
+    }
+
+    pub async fn collect_garbage(&self) -> Result<GcReport, DatabaseError> {
+        self.run(|database| database.collect_garbage()).await
+    }
+}
+
+// Waits for a blocking task, passing on its panic if it had one
+async fn join<T>(handle: JoinHandle<Result<T, DatabaseError>>) -> Result<T, DatabaseError> {
+    match handle.await {
+        Ok(result) => result,
+        Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+        Err(error) => Err(DatabaseError::Other(format!("Background task failed: {}", error))),
+    }
+}
+
+// The blocking end of a stream fed from async code
+struct ChannelReader {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl ChannelReader {
+    fn new(receiver: mpsc::Receiver<io::Result<Vec<u8>>>) -> Self {
+        ChannelReader {
+            receiver,
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            match self.receiver.blocking_recv() {
+                Some(buffer) => {
+                    self.buffer = buffer?;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let count = buf.len().min(self.buffer.len() - self.position);
+        buf[..count].copy_from_slice(&self.buffer[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::ChunkerConfig;
+    use crate::seigrconfig::{KEY_LENGTH, NONCE_LENGTH};
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+    use tempfile::TempDir;
+
+    fn open_database(dir: &TempDir) -> AsyncDatabase {
+        let database = Database::new(&dir.path().to_string_lossy(), &[7u8; KEY_LENGTH], &[0u8; NONCE_LENGTH]).unwrap();
+        AsyncDatabase::with_max_in_flight(Arc::new(database), 2)
+    }
+
+    fn sample_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        StdRng::seed_from_u64(seed).fill_bytes(&mut data);
+        data
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_streams_run_side_by_side_and_can_be_cancelled() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        let (first, second) = (sample_data(300_000, 1), sample_data(200_000, 2));
+
+        let (stored_first, stored_second) = tokio::join!(
+            database.store_reader("first.bin".to_string(), first.as_slice()),
+            database.store_file("second.bin".to_string(), second.clone()),
+        );
+        stored_first.unwrap();
+        stored_second.unwrap();
+        let mut out = Vec::new();
+        assert_eq!(database.retrieve_to_writer("first.bin", &mut out).await.unwrap(), first.len() as u64);
+        assert_eq!(out, first);
+        assert_eq!(database.retrieve_file("second.bin").await.unwrap(), second);
+        assert_eq!(database.available_permits(), 2);
+
+        // A store cancelled half way leaves nothing behind, not even the beecells it cut so far
+        database.database().set_chunker_config(ChunkerConfig::new(1024, 4096, 16384).unwrap()).unwrap();
+        let token = CancellationToken::new();
+        let cancellable = database.with_cancellation(token.clone());
+        let (mut client, server) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+        let store = tokio::spawn(async move { cancellable.store_reader("partial.bin".to_string(), server).await });
+        client.write_all(&sample_data(100_000, 3)).await.unwrap();
+        token.cancel();
+        assert!(matches!(store.await.unwrap(), Err(DatabaseError::Cancelled)));
+        assert!(matches!(database.stat("partial.bin").await, Err(DatabaseError::FileNotFound(_))));
+        assert_eq!(database.run(|database| database.garbage_report()).await.unwrap().beecells, 0);
+
+        // Nothing starts once cancelled
+        let cancelled = database.with_cancellation(token);
+        assert!(matches!(cancelled.retrieve_file("first.bin").await, Err(DatabaseError::Cancelled)));
+        assert!(database.scrub().await.unwrap().is_clean());
+    }
+}
@@ -0,0 +1,654 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::acl::{Acl, Group, Permission, Principal};
+use crate::chunker::ChunkerConfig;
+use crate::codec::CompressionPolicy;
+use crate::crypto::WrappedKey;
+use crate::database::{Cube, DatabaseError};
+use crate::erasure::ErasureConfig;
+use crate::namespace::{is_within, parent_dirs, rebase};
+use crate::query::{metadata_term, tag_term, MetadataIndex};
+use crate::quota::UsageIndex;
+use crate::snapshot::{Snapshot, SnapshotFile};
+
+/// Everything about the database that isn't a cube, persisted as `catalog.json`.
+///
+/// The catalog only ever changes through [`CatalogOp`]s, which are journaled first so that a
+/// batch of them lands completely or not at all.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Catalog {
+    pub(crate) file_links: HashMap<String, Vec<String>>,
+    // Kept with the hive so that every session cuts files the same way
+    #[serde(default)]
+    pub(crate) chunker: ChunkerConfig,
+    #[serde(default)]
+    pub(crate) compression: CompressionPolicy,
+    // Parity added to the frames of new cubes, if any
+    #[serde(default)]
+    pub(crate) erasure: Option<ErasureConfig>,
+    // How many file references each stored beecell address has, a beecell is deleted at zero
+    #[serde(default)]
+    pub(crate) refcounts: HashMap<String, u64>,
+    #[serde(default)]
+    pub(crate) metadata: HashMap<String, BTreeMap<String, String>>,
+    #[serde(default)]
+    pub(crate) tags: HashMap<String, BTreeSet<String>>,
+    // Tags and metadata values to files, always in step with the two maps above
+    #[serde(default)]
+    pub(crate) index: MetadataIndex,
+    // Content hash to the files and versions with that content. It is rebuilt from `versions`
+    // on load rather than stored.
+    #[serde(skip)]
+    pub(crate) content_index: HashMap<String, BTreeSet<(String, u64)>>,
+    // What every owner stores, for quotas. Like the content index it is rebuilt on load.
+    #[serde(skip)]
+    pub(crate) usage: UsageIndex,
+    // Data keys of the files that are encrypted
+    #[serde(default)]
+    pub(crate) keys: HashMap<String, WrappedKey>,
+    // Every version of every file, oldest first. The last one is what `file_links` points at.
+    #[serde(default)]
+    pub(crate) versions: HashMap<String, Vec<FileVersion>>,
+    #[serde(default)]
+    pub(crate) snapshots: BTreeMap<String, Snapshot>,
+    // Every directory, whether made explicitly or above a stored file. It stays when its files
+    // are deleted, until it is removed itself.
+    #[serde(default)]
+    pub(crate) directories: BTreeSet<String>,
+    // Files without an ACL have no owner, and anyone may do anything with them
+    #[serde(default)]
+    pub(crate) acls: HashMap<String, Acl>,
+    #[serde(default)]
+    pub(crate) groups: BTreeMap<String, Group>,
+    // Id of the last transaction applied, so a replayed journal is never applied twice
+    #[serde(default)]
+    pub(crate) last_txid: u64,
+}
+
+/// One stored version of a file, see [`Database::list_versions`].
+///
+/// [`Database::list_versions`]: crate::database::Database::list_versions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileVersion {
+    /// Counts up from 1 for every store of the same filename.
+    pub version: u64,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Beeid of the user who stored it, empty if nobody was signed in.
+    pub author: String,
+    pub size: u64,
+    /// Blake2b hash of the whole content, empty for versions stored before it was recorded and
+    /// for encrypted versions imported without their owner.
+    #[serde(default)]
+    pub content_hash: String,
+    pub(crate) cube_ids: Vec<String>,
+    #[serde(default)]
+    pub(crate) key: Option<WrappedKey>,
+}
+
+/// A single change to the catalog. Any beecells and cubes it refers to are already in the hive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum CatalogOp {
+    Link {
+        filename: String,
+        cube_ids: Vec<String>,
+        #[serde(default)]
+        key: Option<WrappedKey>,
+        #[serde(default)]
+        timestamp: u64,
+        #[serde(default)]
+        author: String,
+        #[serde(default)]
+        content_hash: String,
+    },
+    Restore { filename: String, version: u64, timestamp: u64, author: String },
+    CreateSnapshot {
+        name: String,
+        timestamp: u64,
+        #[serde(default)]
+        owner: String,
+    },
+    DeleteSnapshot { name: String },
+    Unlink { filename: String },
+    /// Renames or moves a file, or a directory with everything in it.
+    Rename { from: String, to: String },
+    /// Makes a directory, owned by `owner` if not empty.
+    MakeDirectory {
+        path: String,
+        #[serde(default)]
+        owner: String,
+    },
+    RemoveDirectory { path: String },
+    // Whole ACLs and groups were set by older versions, their journals may still hold these
+    SetAcl { filename: String, acl: Option<Acl> },
+    SetGroup { name: String, group: Option<Group> },
+    /// Gives `principal` `permission` on a file or directory that has an owner.
+    GrantAcl { filename: String, principal: Principal, permission: Permission },
+    RevokeAcl { filename: String, principal: Principal },
+    SetOwner { filename: String, owner: String },
+    /// Adds `beeid` to a group, which is created owned by `owner` if it is new.
+    AddMember { group: String, beeid: String, owner: String },
+    /// Removes `beeid` from a group, the group goes away with its last member.
+    RemoveMember { group: String, beeid: String },
+    SetMetadata { filename: String, key: String, value: Option<String> },
+    SetTag { filename: String, tag: String, present: bool },
+    /// Adds a file from an archive with its whole history, owned by `owner` if not empty.
+    Import {
+        filename: String,
+        versions: Vec<FileVersion>,
+        metadata: BTreeMap<String, String>,
+        tags: BTreeSet<String>,
+        owner: String,
+    },
+}
+
+/// What `journal.json` holds while a transaction is being committed.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct JournalEntry {
+    pub(crate) txid: u64,
+    pub(crate) ops: Vec<CatalogOp>,
+}
+
+impl Catalog {
+    /// Gives files linked before versions were kept a first version, so that every file has one.
+    pub(crate) fn migrate_versions(&mut self, cubes: &HashMap<String, Arc<Cube>>) {
+        for (filename, cube_ids) in &self.file_links {
+            if !self.versions.contains_key(filename) {
+                let version = FileVersion {
+                    version: 1,
+                    timestamp: 0,
+                    author: String::new(),
+                    size: cubes_size(cubes, cube_ids),
+                    content_hash: String::new(),
+                    cube_ids: cube_ids.clone(),
+                    key: self.keys.get(filename).cloned(),
+                };
+                self.versions.insert(filename.clone(), vec![version]);
+            }
+        }
+    }
+
+    /// Adds the directories above files linked before directories were kept.
+    pub(crate) fn migrate_directories(&mut self) {
+        let parents: Vec<String> = self.file_links.keys().flat_map(|filename| parent_dirs(filename)).filter(|dir| !dir.is_empty()).map(str::to_string).collect();
+        self.directories.extend(parents);
+    }
+
+    /// Builds the metadata index of a catalog written before it was kept.
+    pub(crate) fn migrate_index(&mut self) {
+        if self.index.is_empty() {
+            let filenames: Vec<String> = self.metadata.keys().chain(self.tags.keys()).cloned().collect();
+            for filename in filenames {
+                for term in self.index_terms(&filename) {
+                    self.index.insert(term, &filename);
+                }
+            }
+        }
+    }
+
+    /// Fills the content index from the versions, which is all it is derived from.
+    pub(crate) fn build_content_index(&mut self) {
+        self.content_index.clear();
+        for (filename, versions) in &self.versions {
+            index_content(&mut self.content_index, filename, versions);
+        }
+    }
+
+    /// Works out the usage of every owner from the files and versions.
+    pub(crate) fn build_usage(&mut self, cubes: &HashMap<String, Arc<Cube>>) {
+        self.usage = UsageIndex::build(self, cubes);
+    }
+
+    fn unindex_content(&mut self, filename: &str, versions: &[FileVersion]) {
+        for version in versions {
+            if let Some(entries) = self.content_index.get_mut(&version.content_hash) {
+                entries.remove(&(filename.to_string(), version.version));
+                if entries.is_empty() {
+                    self.content_index.remove(&version.content_hash);
+                }
+            }
+        }
+    }
+
+    fn index_terms(&self, filename: &str) -> Vec<String> {
+        let tags = self.tags.get(filename).into_iter().flatten().map(|tag| tag_term(tag));
+        let metadata = self.metadata.get(filename).into_iter().flatten().map(|(key, value)| metadata_term(key, value));
+        tags.chain(metadata).collect()
+    }
+
+    /// Makes files stored by a signed-in user before ACLs were kept owned by that user.
+    pub(crate) fn migrate_acls(&mut self) {
+        for (filename, versions) in &self.versions {
+            match versions.first() {
+                Some(first) if !first.author.is_empty() && !self.acls.contains_key(filename) => {
+                    self.acls.insert(filename.clone(), Acl::new(first.author.clone()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Applies `op`, returning the beecell addresses that are no longer referenced.
+    pub(crate) fn apply(&mut self, cubes: &HashMap<String, Arc<Cube>>, op: &CatalogOp) -> Result<Vec<String>, DatabaseError> {
+        // The file whose versions or owner the op changes is taken off the usage of its owner and
+        // charged again afterwards
+        let charged = match op {
+            CatalogOp::Link { filename, .. }
+            | CatalogOp::Restore { filename, .. }
+            | CatalogOp::Unlink { filename }
+            | CatalogOp::SetAcl { filename, .. }
+            | CatalogOp::SetOwner { filename, .. }
+            | CatalogOp::Import { filename, .. } => Some(filename.as_str()),
+            _ => None,
+        };
+        if let Some(filename) = charged {
+            self.charge_usage(cubes, filename, false);
+        }
+        let released = self.apply_op(cubes, op);
+        if let Some(filename) = charged {
+            self.charge_usage(cubes, filename, true);
+        }
+        released
+    }
+
+    fn charge_usage(&mut self, cubes: &HashMap<String, Arc<Cube>>, filename: &str, add: bool) {
+        if let (Some(acl), Some(versions)) = (self.acls.get(filename), self.versions.get(filename)) {
+            self.usage.charge(&acl.owner, versions, cubes, add);
+        }
+    }
+
+    fn apply_op(&mut self, cubes: &HashMap<String, Arc<Cube>>, op: &CatalogOp) -> Result<Vec<String>, DatabaseError> {
+        match op {
+            CatalogOp::Link { filename, cube_ids, key, timestamp, author, content_hash } => {
+                if !self.file_links.contains_key(filename) {
+                    self.check_path_is_free(filename)?;
+                    self.add_parent_dirs(filename);
+                    // Whoever stores a file first owns it
+                    if !author.is_empty() {
+                        self.acls.insert(filename.clone(), Acl::new(author.clone()));
+                    }
+                }
+                // Every version holds its own references, so nothing is released here
+                self.retain_cubes(cubes, cube_ids)?;
+                let version = FileVersion {
+                    version: 0,
+                    timestamp: *timestamp,
+                    author: author.clone(),
+                    size: cubes_size(cubes, cube_ids),
+                    content_hash: content_hash.clone(),
+                    cube_ids: cube_ids.clone(),
+                    key: key.clone(),
+                };
+                self.push_version(filename, version);
+                Ok(Vec::new())
+            }
+            CatalogOp::Restore { filename, version, timestamp, author } => {
+                let versions = self.versions.get(filename).ok_or(DatabaseError::FileNotFound(filename.clone()))?;
+                let restored = versions
+                    .iter()
+                    .find(|candidate| candidate.version == *version)
+                    .ok_or(DatabaseError::VersionNotFound(filename.clone(), *version))?
+                    .clone();
+                self.retain_cubes(cubes, &restored.cube_ids)?;
+                let version = FileVersion {
+                    timestamp: *timestamp,
+                    author: author.clone(),
+                    ..restored
+                };
+                self.push_version(filename, version);
+                Ok(Vec::new())
+            }
+            CatalogOp::Unlink { filename } => {
+                self.file_links.remove(filename).ok_or(DatabaseError::FileNotFound(filename.clone()))?;
+                for term in self.index_terms(filename) {
+                    self.index.remove(&term, filename);
+                }
+                self.metadata.remove(filename);
+                self.tags.remove(filename);
+                self.keys.remove(filename);
+                self.acls.remove(filename);
+                // The history goes with the file
+                let mut released = Vec::new();
+                let versions = self.versions.remove(filename).unwrap_or_default();
+                self.unindex_content(filename, &versions);
+                for version in versions {
+                    released.extend(self.release_cubes(cubes, &version.cube_ids));
+                }
+                Ok(released)
+            }
+            CatalogOp::Rename { from, to } => {
+                self.check_path_is_free(to)?;
+                if self.file_links.contains_key(from) {
+                    self.move_file(from, to);
+                } else if self.directories.contains(from) {
+                    if is_within(to, from) {
+                        return Err(DatabaseError::InvalidPath(to.clone()));
+                    }
+                    let filenames: Vec<String> = self.file_links.keys().filter(|filename| is_within(filename, from)).cloned().collect();
+                    for filename in filenames {
+                        self.move_file(&filename, &rebase(&filename, from, to));
+                    }
+                    let directories: Vec<String> = self.directories.iter().filter(|dir| *dir == from || is_within(dir, from)).cloned().collect();
+                    for dir in directories {
+                        self.directories.remove(&dir);
+                        let moved = rebase(&dir, from, to);
+                        if let Some(acl) = self.acls.remove(&dir) {
+                            self.acls.insert(moved.clone(), acl);
+                        }
+                        self.directories.insert(moved);
+                    }
+                } else {
+                    return Err(DatabaseError::FileNotFound(from.clone()));
+                }
+                self.add_parent_dirs(to);
+                Ok(Vec::new())
+            }
+            CatalogOp::MakeDirectory { path, owner } => {
+                self.check_path_is_free(path)?;
+                self.add_parent_dirs(path);
+                self.directories.insert(path.clone());
+                if !owner.is_empty() {
+                    self.acls.insert(path.clone(), Acl::new(owner.clone()));
+                }
+                Ok(Vec::new())
+            }
+            CatalogOp::RemoveDirectory { path } => {
+                if !self.directories.contains(path) {
+                    return Err(DatabaseError::DirectoryNotFound(path.clone()));
+                }
+                let mut children = self.file_links.keys().chain(&self.directories);
+                if children.any(|child| is_within(child, path)) {
+                    return Err(DatabaseError::DirectoryNotEmpty(path.clone()));
+                }
+                self.directories.remove(path);
+                self.acls.remove(path);
+                Ok(Vec::new())
+            }
+            CatalogOp::SetAcl { filename, acl } => {
+                self.check_exists(filename)?;
+                match acl {
+                    Some(acl) => self.acls.insert(filename.clone(), acl.clone()),
+                    None => self.acls.remove(filename),
+                };
+                Ok(Vec::new())
+            }
+            CatalogOp::SetGroup { name, group } => {
+                match group {
+                    Some(group) => self.groups.insert(name.clone(), group.clone()),
+                    None => self.groups.remove(name),
+                };
+                Ok(Vec::new())
+            }
+            CatalogOp::GrantAcl { filename, principal, permission } => {
+                self.owned_acl(filename)?.grant(principal.clone(), *permission);
+                Ok(Vec::new())
+            }
+            CatalogOp::RevokeAcl { filename, principal } => {
+                self.owned_acl(filename)?.revoke(principal);
+                Ok(Vec::new())
+            }
+            CatalogOp::SetOwner { filename, owner } => {
+                self.check_exists(filename)?;
+                self.acls.entry(filename.clone()).or_insert_with(|| Acl::new(String::new())).owner = owner.clone();
+                Ok(Vec::new())
+            }
+            CatalogOp::AddMember { group, beeid, owner } => {
+                let group = self.groups.entry(group.clone()).or_insert_with(|| Group { owner: owner.clone(), members: BTreeSet::new() });
+                group.members.insert(beeid.clone());
+                Ok(Vec::new())
+            }
+            CatalogOp::RemoveMember { group, beeid } => {
+                let members = &mut self.groups.get_mut(group).ok_or(DatabaseError::Other(format!("Group not found: {}", group)))?.members;
+                members.remove(beeid);
+                if members.is_empty() {
+                    self.groups.remove(group);
+                }
+                Ok(Vec::new())
+            }
+            CatalogOp::CreateSnapshot { name, timestamp, owner } => {
+                if self.snapshots.contains_key(name) {
+                    return Err(DatabaseError::SnapshotExists(name.clone()));
+                }
+                let mut files = BTreeMap::new();
+                for (filename, cube_ids) in &self.file_links {
+                    let file = SnapshotFile {
+                        cube_ids: cube_ids.clone(),
+                        key: self.keys.get(filename).cloned(),
+                        acl: self.acls.get(filename).cloned(),
+                        content_hash: self.versions.get(filename).and_then(|versions| versions.last()).map(|version| version.content_hash.clone()).unwrap_or_default(),
+                    };
+                    files.insert(filename.clone(), file);
+                }
+                for file in files.values() {
+                    self.retain_cubes(cubes, &file.cube_ids)?;
+                }
+                let snapshot = Snapshot {
+                    name: name.clone(),
+                    timestamp: *timestamp,
+                    owner: owner.clone(),
+                    files,
+                };
+                self.snapshots.insert(name.clone(), snapshot);
+                Ok(Vec::new())
+            }
+            CatalogOp::DeleteSnapshot { name } => {
+                let snapshot = self.snapshots.remove(name).ok_or(DatabaseError::SnapshotNotFound(name.clone()))?;
+                let mut released = Vec::new();
+                for file in snapshot.files.values() {
+                    released.extend(self.release_cubes(cubes, &file.cube_ids));
+                }
+                Ok(released)
+            }
+            CatalogOp::SetMetadata { filename, key, value } => {
+                if !self.file_links.contains_key(filename) {
+                    return Err(DatabaseError::FileNotFound(filename.clone()));
+                }
+                let metadata = self.metadata.entry(filename.clone()).or_default();
+                let previous = match value {
+                    Some(value) => metadata.insert(key.clone(), value.clone()),
+                    None => metadata.remove(key),
+                };
+                if metadata.is_empty() {
+                    self.metadata.remove(filename);
+                }
+                if let Some(previous) = previous {
+                    self.index.remove(&metadata_term(key, &previous), filename);
+                }
+                if let Some(value) = value {
+                    self.index.insert(metadata_term(key, value), filename);
+                }
+                Ok(Vec::new())
+            }
+            CatalogOp::SetTag { filename, tag, present } => {
+                if !self.file_links.contains_key(filename) {
+                    return Err(DatabaseError::FileNotFound(filename.clone()));
+                }
+                let tags = self.tags.entry(filename.clone()).or_default();
+                if *present {
+                    tags.insert(tag.clone());
+                    self.index.insert(tag_term(tag), filename);
+                } else {
+                    tags.remove(tag);
+                    if tags.is_empty() {
+                        self.tags.remove(filename);
+                    }
+                    self.index.remove(&tag_term(tag), filename);
+                }
+                Ok(Vec::new())
+            }
+            CatalogOp::Import { filename, versions, metadata, tags, owner } => {
+                self.check_path_is_free(filename)?;
+                let current = versions.last().ok_or_else(|| DatabaseError::InvalidArchive(format!("{} has no versions", filename)))?;
+                self.add_parent_dirs(filename);
+                if !owner.is_empty() {
+                    self.acls.insert(filename.clone(), Acl::new(owner.clone()));
+                }
+                self.file_links.insert(filename.clone(), current.cube_ids.clone());
+                if let Some(key) = &current.key {
+                    self.keys.insert(filename.clone(), key.clone());
+                }
+
+                let mut imported = Vec::with_capacity(versions.len());
+                for version in versions {
+                    self.retain_cubes(cubes, &version.cube_ids)?;
+                    // Sizes are taken from the cubes rather than trusted
+                    imported.push(FileVersion {
+                        size: cubes_size(cubes, &version.cube_ids),
+                        ..version.clone()
+                    });
+                }
+                index_content(&mut self.content_index, filename, &imported);
+                self.versions.insert(filename.clone(), imported);
+
+                for (key, value) in metadata {
+                    self.index.insert(metadata_term(key, value), filename);
+                }
+                if !metadata.is_empty() {
+                    self.metadata.insert(filename.clone(), metadata.clone());
+                }
+                for tag in tags {
+                    self.index.insert(tag_term(tag), filename);
+                }
+                if !tags.is_empty() {
+                    self.tags.insert(filename.clone(), tags.clone());
+                }
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    // Every list of cubes the catalog holds references for: each version of each file and each
+    // file of each snapshot
+    fn referenced_cube_lists(&self) -> impl Iterator<Item = &Vec<String>> {
+        let versions = self.versions.values().flatten().map(|version| &version.cube_ids);
+        let snapshots = self.snapshots.values().flat_map(|snapshot| snapshot.files.values()).map(|file| &file.cube_ids);
+        versions.chain(snapshots)
+    }
+
+    /// Ids of the cubes some file, version or snapshot still points at.
+    pub(crate) fn reachable_cube_ids(&self) -> HashSet<&str> {
+        self.referenced_cube_lists().flatten().map(String::as_str).collect()
+    }
+
+    /// Counts the references to every beecell and parity shard from scratch.
+    pub(crate) fn count_references(&self, cubes: &HashMap<String, Arc<Cube>>) -> HashMap<String, u64> {
+        let mut refcounts = HashMap::new();
+        for cube_id in self.referenced_cube_lists().flatten() {
+            for address in cubes.get(cube_id).into_iter().flat_map(|cube| cube.addresses()) {
+                *refcounts.entry(address.to_string()).or_insert(0) += 1;
+            }
+        }
+        refcounts
+    }
+
+    fn check_exists(&self, path: &str) -> Result<(), DatabaseError> {
+        if !self.file_links.contains_key(path) && !self.directories.contains(path) {
+            return Err(DatabaseError::FileNotFound(path.to_string()));
+        }
+        Ok(())
+    }
+
+    // The ACL of a file or directory that has an owner, for changing it
+    fn owned_acl(&mut self, path: &str) -> Result<&mut Acl, DatabaseError> {
+        self.check_exists(path)?;
+        self.acls.get_mut(path).ok_or(DatabaseError::Other(format!("{} has no owner", path)))
+    }
+
+    // A new file or directory can't take the place of another, nor sit below a file
+    fn check_path_is_free(&self, path: &str) -> Result<(), DatabaseError> {
+        if path.is_empty() || self.file_links.contains_key(path) || self.directories.contains(path) {
+            return Err(DatabaseError::FileExists(path.to_string()));
+        }
+        match parent_dirs(path).find(|parent| self.file_links.contains_key(*parent)) {
+            Some(parent) => Err(DatabaseError::FileExists(parent.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    fn add_parent_dirs(&mut self, path: &str) {
+        self.directories.extend(parent_dirs(path).map(str::to_string));
+    }
+
+    fn move_file(&mut self, from: &str, to: &str) {
+        for term in self.index_terms(from) {
+            self.index.remove(&term, from);
+            self.index.insert(term, to);
+        }
+        if let Some(tags) = self.tags.remove(from) {
+            self.tags.insert(to.to_string(), tags);
+        }
+        if let Some(cube_ids) = self.file_links.remove(from) {
+            self.file_links.insert(to.to_string(), cube_ids);
+        }
+        if let Some(metadata) = self.metadata.remove(from) {
+            self.metadata.insert(to.to_string(), metadata);
+        }
+        if let Some(key) = self.keys.remove(from) {
+            self.keys.insert(to.to_string(), key);
+        }
+        if let Some(versions) = self.versions.remove(from) {
+            self.unindex_content(from, &versions);
+            index_content(&mut self.content_index, to, &versions);
+            self.versions.insert(to.to_string(), versions);
+        }
+        if let Some(acl) = self.acls.remove(from) {
+            self.acls.insert(to.to_string(), acl);
+        }
+    }
+
+    // Makes `version` the current version of `filename`
+    fn push_version(&mut self, filename: &str, mut version: FileVersion) {
+        let versions = self.versions.entry(filename.to_string()).or_default();
+        version.version = versions.last().map_or(1, |last| last.version + 1);
+        self.file_links.insert(filename.to_string(), version.cube_ids.clone());
+        match &version.key {
+            Some(key) => self.keys.insert(filename.to_string(), key.clone()),
+            None => self.keys.remove(filename),
+        };
+        index_content(&mut self.content_index, filename, std::slice::from_ref(&version));
+        versions.push(version);
+    }
+
+    fn retain_cubes(&mut self, cubes: &HashMap<String, Arc<Cube>>, cube_ids: &[String]) -> Result<(), DatabaseError> {
+        for cube_id in cube_ids {
+            let cube = cubes.get(cube_id).ok_or(DatabaseError::CubeNotFound(cube_id.clone()))?;
+            for address in cube.addresses() {
+                *self.refcounts.entry(address.to_string()).or_insert(0) += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Drops one reference from every beecell of the given cubes and returns the addresses that
+    // reached zero. They are only removed from the hive once the catalog no longer needs them.
+    fn release_cubes(&mut self, cubes: &HashMap<String, Arc<Cube>>, cube_ids: &[String]) -> Vec<String> {
+        let mut released = Vec::new();
+        for cube_id in cube_ids {
+            let Some(cube) = cubes.get(cube_id) else { continue };
+            for address in cube.addresses() {
+                if let Some(count) = self.refcounts.get_mut(address) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.refcounts.remove(address);
+                        released.push(address.to_string());
+                    }
+                }
+            }
+        }
+        released
+    }
+}
+
+fn index_content(index: &mut HashMap<String, BTreeSet<(String, u64)>>, filename: &str, versions: &[FileVersion]) {
+    // Versions stored before content hashes were recorded can't be found by hash
+    for version in versions.iter().filter(|version| !version.content_hash.is_empty()) {
+        index.entry(version.content_hash.clone()).or_default().insert((filename.to_string(), version.version));
+    }
+}
+
+fn cubes_size(cubes: &HashMap<String, Arc<Cube>>, cube_ids: &[String]) -> u64 {
+    cube_ids.iter().filter_map(|cube_id| cubes.get(cube_id)).map(|cube| cube.size).sum()
+}
@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+
+/// Largest `max_size` a [`ChunkerConfig`] accepts, and so the longest a beecell can be.
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Size limits for content-defined chunking, in bytes.
+///
+/// Chunks are never shorter than `min_size` (except the last one of a file) and never longer
+/// than `max_size`; boundaries are picked so that chunks average around `avg_size`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkerConfig {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        ChunkerConfig {
+            min_size: 256 * 1024,
+            avg_size: 1024 * 1024,
+            max_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl ChunkerConfig {
+    pub fn new(min_size: usize, avg_size: usize, max_size: usize) -> Result<Self, String> {
+        let config = ChunkerConfig { min_size, avg_size, max_size };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_size == 0 {
+            return Err("Minimum chunk size must be greater than zero".to_string());
+        }
+        if self.min_size > self.avg_size || self.avg_size > self.max_size {
+            return Err(format!(
+                "Chunk sizes must satisfy min <= avg <= max, got {} / {} / {}",
+                self.min_size, self.avg_size, self.max_size
+            ));
+        }
+        if self.max_size > MAX_CHUNK_SIZE {
+            return Err(format!("Maximum chunk size can't exceed {} bytes, got {}", MAX_CHUNK_SIZE, self.max_size));
+        }
+        Ok(())
+    }
+}
+
+/// FastCDC style content-defined chunker.
+///
+/// A gear rolling hash runs over the data and a boundary is cut wherever the hash matches a
+/// mask, so boundaries follow the content: inserting a byte only changes the chunks around it
+/// instead of shifting every chunk after it. A stricter mask is used before `avg_size` and a
+/// looser one after it, which keeps chunk sizes close to the average.
+#[derive(Debug, Clone)]
+pub struct Chunker {
+    config: ChunkerConfig,
+    mask_small: u64,
+    mask_large: u64,
+}
+
+impl Chunker {
+    pub fn new(config: ChunkerConfig) -> Self {
+        let bits = config.avg_size.max(2).ilog2();
+        Chunker {
+            config,
+            mask_small: high_bits_mask(bits + 2),
+            mask_large: high_bits_mask(bits.saturating_sub(2).max(1)),
+        }
+    }
+
+    pub fn config(&self) -> ChunkerConfig {
+        self.config
+    }
+
+    /// Finds where the chunk starting at `data[0]` ends.
+    ///
+    /// Returns `None` if no boundary was found and `data` is shorter than `max_size`: the caller
+    /// should either supply more data or, at the end of the input, use all of `data` as the last
+    /// chunk.
+    pub fn next_boundary(&self, data: &[u8]) -> Option<usize> {
+        let ChunkerConfig { min_size, avg_size, max_size } = self.config;
+        if data.len() >= max_size && min_size == max_size {
+            return Some(max_size);
+        }
+        if data.len() <= min_size {
+            return None;
+        }
+
+        let end = data.len().min(max_size);
+        let normal = avg_size.min(end);
+        let mut hash = 0u64;
+
+        for (i, byte) in data.iter().enumerate().take(normal).skip(min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & self.mask_small == 0 {
+                return Some(i + 1);
+            }
+        }
+        for (i, byte) in data.iter().enumerate().take(end).skip(normal) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & self.mask_large == 0 {
+                return Some(i + 1);
+            }
+        }
+
+        if end == max_size {
+            Some(max_size)
+        } else {
+            None
+        }
+    }
+
+    /// Splits a complete buffer into chunks.
+    pub fn split<'a>(&self, data: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut chunks = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let len = self.next_boundary(rest).unwrap_or(rest.len());
+            let (chunk, tail) = rest.split_at(len);
+            chunks.push(chunk);
+            rest = tail;
+        }
+        chunks
+    }
+}
+
+// The top bits of the gear hash depend on the most bytes, so masks are taken from there
+fn high_bits_mask(bits: u32) -> u64 {
+    let bits = bits.min(63);
+    !0u64 << (64 - bits)
+}
+
+// Random-looking but fixed table, boundaries must not change between runs
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x5365_6967_7248_6976u64; // "SeigrHiv"
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        StdRng::seed_from_u64(42).fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn test_chunks_respect_size_limits() {
+        let config = ChunkerConfig::new(256, 1024, 4096).unwrap();
+        let chunker = Chunker::new(config);
+        let data = random_data(200_000);
+
+        let chunks = chunker.split(&data);
+        assert_eq!(chunks.concat(), data);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= config.min_size && chunk.len() <= config.max_size);
+        }
+    }
+
+    #[test]
+    fn test_insert_keeps_most_chunks() {
+        let chunker = Chunker::new(ChunkerConfig::new(256, 1024, 4096).unwrap());
+        let data = random_data(200_000);
+        let mut edited = data.clone();
+        edited.insert(100, 0xAB);
+
+        let original: std::collections::HashSet<&[u8]> = chunker.split(&data).into_iter().collect();
+        let edited_chunks = chunker.split(&edited);
+        let reused = edited_chunks.iter().filter(|chunk| original.contains(*chunk)).count();
+        assert!(reused + 3 >= edited_chunks.len(), "only {} of {} chunks reused", reused, edited_chunks.len());
+    }
+
+    #[test]
+    fn test_invalid_config_is_rejected() {
+        assert!(ChunkerConfig::new(0, 1024, 4096).is_err());
+        assert!(ChunkerConfig::new(2048, 1024, 4096).is_err());
+        assert!(ChunkerConfig::new(1024, 4096, MAX_CHUNK_SIZE + 1).is_err());
+    }
+}
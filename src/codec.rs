@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{CellPosition, DataKey, SEAL_OVERHEAD};
+use crate::database::DatabaseError;
+use crate::namespace::base_name;
+
+// Every stored beecell that isn't kept raw starts with this header, which the codec and whether
+// the payload is sealed are read from
+const HEADER_MAGIC: &[u8; 4] = b"SHBC";
+const HEADER_VERSION: u8 = 1;
+const HEADER_LENGTH: usize = HEADER_MAGIC.len() + 3;
+
+/// How much longer than its payload [`encode_beecell`] can make a beecell, compression only
+/// being kept when it makes it shorter.
+pub(crate) const MAX_ENCODING_OVERHEAD: usize = HEADER_LENGTH + SEAL_OVERHEAD;
+
+/// How the payload of a beecell is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+            Codec::Lz4 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+
+    fn compress(self, data: &[u8], level: i32) -> Result<Vec<u8>, DatabaseError> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Zstd => zstd::bulk::compress(data, level).map_err(DatabaseError::from),
+            Codec::Lz4 => Ok(lz4_flex::compress(data)),
+        }
+    }
+
+    // `size` is what the beecell held before compression, nothing larger is ever allocated
+    fn decompress(self, data: &[u8], size: usize) -> Result<Vec<u8>, DatabaseError> {
+        let decompressed = match self {
+            Codec::None => return Ok(data.to_vec()),
+            Codec::Zstd => zstd::bulk::decompress(data, size).map_err(|error| error.to_string()),
+            Codec::Lz4 => lz4_flex::decompress(data, size).map_err(|error| error.to_string()),
+        };
+        decompressed.map_err(|error| DatabaseError::IntegrityViolation(format!("beecell failed to decompress: {}", error)))
+    }
+}
+
+/// Which codec new beecells are compressed with.
+///
+/// `by_extension` overrides `codec` for files ending in `.<extension>`, so that e.g. archives and
+/// media aren't compressed twice. Beecells that don't get smaller are stored as they are.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionPolicy {
+    pub codec: Codec,
+    /// zstd compression level, ignored by the other codecs.
+    pub level: i32,
+    #[serde(default)]
+    pub by_extension: BTreeMap<String, Codec>,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        CompressionPolicy {
+            codec: Codec::None,
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            by_extension: BTreeMap::new(),
+        }
+    }
+}
+
+impl CompressionPolicy {
+    pub fn new(codec: Codec) -> Self {
+        CompressionPolicy { codec, ..Self::default() }
+    }
+
+    pub fn with_extension(mut self, extension: &str, codec: Codec) -> Self {
+        self.by_extension.insert(extension.to_lowercase(), codec);
+        self
+    }
+
+    pub fn codec_for(&self, filename: &str) -> Codec {
+        // Only the name of the file itself counts, not a dot in a directory above it
+        let extension = base_name(filename).rsplit_once('.').map(|(_, extension)| extension.to_lowercase());
+        extension.and_then(|extension| self.by_extension.get(&extension).copied()).unwrap_or(self.codec)
+    }
+}
+
+/// Turns the payload of a beecell into what is stored in the hive.
+///
+/// Returns `None` when the payload is best stored raw: it isn't encrypted and compressing it
+/// didn't make it any smaller.
+pub(crate) fn encode_beecell(
+    data: &[u8],
+    codec: Codec,
+    level: i32,
+    key: Option<&DataKey>,
+    position: CellPosition,
+) -> Result<Option<Vec<u8>>, DatabaseError> {
+    let (codec, payload) = match codec.compress(data, level)? {
+        compressed if codec != Codec::None && compressed.len() + HEADER_LENGTH < data.len() => (codec, compressed),
+        _ => (Codec::None, data.to_vec()),
+    };
+    if codec == Codec::None && key.is_none() {
+        return Ok(None);
+    }
+
+    let header = [&HEADER_MAGIC[..], &[HEADER_VERSION, codec.id(), key.is_some() as u8]].concat();
+    let body = match key {
+        Some(key) => key.seal(&header, position, &payload)?,
+        None => payload,
+    };
+    Ok(Some([header, body].concat()))
+}
+
+/// Reverses [`encode_beecell`], `size` being the length of the original payload.
+pub(crate) fn decode_beecell(object: &[u8], size: u64, key: Option<&DataKey>, position: CellPosition) -> Result<Vec<u8>, DatabaseError> {
+    if object.len() < HEADER_LENGTH || &object[..HEADER_MAGIC.len()] != HEADER_MAGIC || object[HEADER_MAGIC.len()] != HEADER_VERSION {
+        return Err(DatabaseError::IntegrityViolation("beecell has no valid header".to_string()));
+    }
+    let (header, body) = object.split_at(HEADER_LENGTH);
+    let codec = Codec::from_id(header[HEADER_LENGTH - 2])
+        .ok_or_else(|| DatabaseError::IntegrityViolation(format!("beecell uses unknown codec {}", header[HEADER_LENGTH - 2])))?;
+
+    let payload = match (header[HEADER_LENGTH - 1] != 0, key) {
+        (true, Some(key)) => key.open(header, position, body)?,
+        (false, None) => body.to_vec(),
+        (true, None) => return Err(DatabaseError::IntegrityViolation("beecell is encrypted but its file has no key".to_string())),
+        (false, Some(_)) => return Err(DatabaseError::IntegrityViolation("beecell of an encrypted file is not encrypted".to_string())),
+    };
+    codec.decompress(&payload, size as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+
+    fn log_lines() -> Vec<u8> {
+        (0..2000).flat_map(|i| format!("2024-01-01T00:00:{:02} INFO request {} served\n", i % 60, i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn test_every_codec_round_trips() {
+        let data = log_lines();
+        let key = DataKey::generate().unwrap();
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            for key in [None, Some(&key)] {
+                let object = encode_beecell(&data, codec, 3, key, CellPosition::of(7)).unwrap().unwrap();
+                assert!(object.len() < data.len() / 2, "{:?} only got down to {} bytes", codec, object.len());
+                assert_eq!(decode_beecell(&object, data.len() as u64, key, CellPosition::of(7)).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_incompressible_beecells_are_stored_raw() {
+        let mut data = vec![0u8; 4096];
+        StdRng::seed_from_u64(1).fill_bytes(&mut data);
+        assert!(encode_beecell(&data, Codec::Zstd, 3, None, CellPosition::of(0)).unwrap().is_none());
+        assert!(encode_beecell(&log_lines(), Codec::None, 3, None, CellPosition::of(0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_policy_picks_codec_by_extension() {
+        let policy = CompressionPolicy::new(Codec::Zstd).with_extension("ZIP", Codec::None);
+        assert_eq!(policy.codec_for("logs/app.log"), Codec::Zstd);
+        assert_eq!(policy.codec_for("backup.zip"), Codec::None);
+        assert_eq!(policy.codec_for("archive.zip/readme"), Codec::Zstd);
+    }
+}
@@ -0,0 +1,191 @@
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::database::{DatabaseError, CELLS_PER_FRAME, FRAMES_PER_CUBE};
+use crate::seigrconfig::{KEY_LENGTH, NONCE_LENGTH};
+use crate::user::User;
+
+// A sealed beecell starts with its position and nonce, followed by the ciphertext
+const SEALED_HEADER_LENGTH: usize = 8 + 4 + 4 + NONCE_LENGTH;
+
+/// How much longer [`DataKey::seal`] makes what it seals: the header and the AEAD tag.
+pub(crate) const SEAL_OVERHEAD: usize = SEALED_HEADER_LENGTH + 16;
+
+/// Where a beecell sits inside its file: which cube, which frame of that cube and which cell of
+/// that frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CellPosition {
+    pub(crate) cube: u64,
+    pub(crate) frame: u32,
+    pub(crate) cell: u32,
+}
+
+impl CellPosition {
+    /// Position of the beecell at `index`, counting from the start of the file.
+    pub(crate) fn of(index: usize) -> Self {
+        let cells_per_cube = CELLS_PER_FRAME * FRAMES_PER_CUBE;
+        CellPosition {
+            cube: (index / cells_per_cube) as u64,
+            frame: (index % cells_per_cube / CELLS_PER_FRAME) as u32,
+            cell: (index % CELLS_PER_FRAME) as u32,
+        }
+    }
+}
+
+/// The key every beecell of one file is encrypted with. It is only ever stored wrapped.
+#[derive(Clone)]
+pub(crate) struct DataKey {
+    key: [u8; KEY_LENGTH],
+}
+
+impl std::fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("DataKey(..)")
+    }
+}
+
+/// A [`DataKey`] encrypted with the `key` of the user owning the file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct WrappedKey {
+    pub(crate) owner: String,
+    nonce: String,
+    key: String,
+}
+
+fn cipher(key: &[u8; KEY_LENGTH]) -> Result<LessSafeKey, DatabaseError> {
+    let key = UnboundKey::new(&aead::AES_256_GCM, key).map_err(|_| DatabaseError::Other("Invalid encryption key".to_string()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+pub(crate) fn random_bytes<const N: usize>() -> Result<[u8; N], DatabaseError> {
+    let mut bytes = [0u8; N];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| DatabaseError::Other("Failed to generate random bytes".to_string()))?;
+    Ok(bytes)
+}
+
+impl DataKey {
+    pub(crate) fn generate() -> Result<Self, DatabaseError> {
+        Ok(DataKey { key: random_bytes()? })
+    }
+
+    /// Encrypts this key for `owner`. The owner's beeid is authenticated along with it.
+    pub(crate) fn wrap(&self, owner: &User) -> Result<WrappedKey, DatabaseError> {
+        let nonce: [u8; NONCE_LENGTH] = random_bytes()?;
+        let mut key = self.key.to_vec();
+        cipher(&owner.key)?
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(owner.beeid.as_bytes()), &mut key)
+            .map_err(|_| DatabaseError::Other("Failed to wrap data key".to_string()))?;
+        Ok(WrappedKey {
+            owner: owner.beeid.clone(),
+            nonce: hex::encode(nonce),
+            key: hex::encode(key),
+        })
+    }
+
+    /// Encrypts one beecell. `prefix` (the beecell header) and the position are authenticated
+    /// along with the data, so a sealed beecell moved to another place in the file no longer opens.
+    pub(crate) fn seal(&self, prefix: &[u8], position: CellPosition, data: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+        let nonce: [u8; NONCE_LENGTH] = random_bytes()?;
+        let header = sealed_header(position, &nonce);
+        let mut sealed = data.to_vec();
+        cipher(&self.key)?
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from([prefix, &header].concat()), &mut sealed)
+            .map_err(|_| DatabaseError::Other("Failed to encrypt beecell".to_string()))?;
+        Ok([header, sealed].concat())
+    }
+
+    /// Decrypts a beecell sealed by [`DataKey::seal`], checking that it belongs at `position`.
+    pub(crate) fn open(&self, prefix: &[u8], position: CellPosition, sealed: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+        if sealed.len() < SEALED_HEADER_LENGTH {
+            return Err(DatabaseError::IntegrityViolation("beecell has no valid encryption header".to_string()));
+        }
+        let (header, ciphertext) = sealed.split_at(SEALED_HEADER_LENGTH);
+        let mut nonce = [0u8; NONCE_LENGTH];
+        nonce.copy_from_slice(&header[SEALED_HEADER_LENGTH - NONCE_LENGTH..]);
+        if header != sealed_header(position, &nonce).as_slice() {
+            return Err(DatabaseError::IntegrityViolation(format!(
+                "beecell was sealed for another position than cube {} frame {} cell {}",
+                position.cube, position.frame, position.cell
+            )));
+        }
+
+        let mut data = ciphertext.to_vec();
+        let len = cipher(&self.key)?
+            .open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from([prefix, header].concat()), &mut data)
+            .map_err(|_| DatabaseError::IntegrityViolation("beecell failed to decrypt".to_string()))?
+            .len();
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+impl WrappedKey {
+    /// Decrypts the data key with the key of `owner`, which must be the user it was wrapped for.
+    pub(crate) fn unwrap(&self, owner: &User) -> Result<DataKey, DatabaseError> {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        let (Ok(()), Ok(mut key)) = (hex::decode_to_slice(&self.nonce, &mut nonce), hex::decode(&self.key)) else {
+            return Err(DatabaseError::AuthenticationFailed);
+        };
+        if owner.beeid != self.owner {
+            return Err(DatabaseError::AuthenticationFailed);
+        }
+
+        let unwrapped = cipher(&owner.key)?
+            .open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(owner.beeid.as_bytes()), &mut key)
+            .map_err(|_| DatabaseError::AuthenticationFailed)?;
+        let key = <[u8; KEY_LENGTH]>::try_from(&unwrapped[..]).map_err(|_| DatabaseError::AuthenticationFailed)?;
+        Ok(DataKey { key })
+    }
+}
+
+fn sealed_header(position: CellPosition, nonce: &[u8; NONCE_LENGTH]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(SEALED_HEADER_LENGTH);
+    header.extend_from_slice(&position.cube.to_be_bytes());
+    header.extend_from_slice(&position.frame.to_be_bytes());
+    header.extend_from_slice(&position.cell.to_be_bytes());
+    header.extend_from_slice(nonce);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner(key: u8) -> User {
+        User {
+            beeid: "seigr_bee42".to_string(),
+            key: [key; KEY_LENGTH],
+            ..User::default()
+        }
+    }
+
+    #[test]
+    fn test_sealed_beecell_only_opens_at_its_position() {
+        let key = DataKey::generate().unwrap();
+        let position = CellPosition::of(1234);
+        let sealed = key.seal(b"header", position, b"customer data").unwrap();
+
+        assert!(!sealed.windows(13).any(|window| window == b"customer data"));
+        assert_eq!(key.open(b"header", position, &sealed).unwrap(), b"customer data");
+        assert!(matches!(key.open(b"header", CellPosition::of(1235), &sealed), Err(DatabaseError::IntegrityViolation(_))));
+        assert!(key.open(b"HEADER", position, &sealed).is_err());
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(key.open(b"header", position, &tampered).is_err());
+    }
+
+    #[test]
+    fn test_wrapped_key_needs_the_owner_key() {
+        let key = DataKey::generate().unwrap();
+        let wrapped = key.wrap(&owner(1)).unwrap();
+        let sealed = key.seal(b"", CellPosition::of(0), b"data").unwrap();
+
+        let unwrapped = wrapped.unwrap(&owner(1)).unwrap();
+        assert_eq!(unwrapped.open(b"", CellPosition::of(0), &sealed).unwrap(), b"data");
+        assert!(matches!(wrapped.unwrap(&owner(2)), Err(DatabaseError::AuthenticationFailed)));
+    }
+}
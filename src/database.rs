@@ -1,14 +1,35 @@
 use std::collections::hash_map::DefaultHasher;
-use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::hash::Hash;
 use std::hash::Hasher;
-use std::{fs, result, io};
+use std::io::{self, Read, Write};
 use blake2::{Blake2b, Digest};
 use generic_array::typenum::U64;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::acl::{Acl, Permission, Principal};
+use crate::archive::{ArchiveManifest, ArchiveReader, ArchiveReport, ArchiveWriter, ArchivedCube, ArchivedFile, Record};
+use crate::asyncdatabase::AsyncDatabase;
+use crate::catalog::{Catalog, CatalogOp, FileVersion, JournalEntry};
+use crate::chunker::{Chunker, ChunkerConfig, MAX_CHUNK_SIZE};
+use crate::codec::{decode_beecell, CompressionPolicy, MAX_ENCODING_OVERHEAD};
+use crate::crypto::{CellPosition, DataKey, WrappedKey};
+use crate::erasure::{self, compute_parity, ErasureConfig, ParityStripe};
+use crate::hive::{HiveStore, CATALOG_FILE, CUBES_DIR, JOURNAL_FILE};
+use crate::listing::{paginate, FileListing, FileMatcher, ListOptions};
+use crate::merkle::{merkle_proof, merkle_root, InclusionProof};
+use crate::query::Query;
+use crate::quota::UsageReport;
+use crate::namespace::{base_name, is_within, normalize_filename, normalize_path, parent_dir, parent_dirs, DirEntry, EntryKind};
 use crate::seigrconfig::SeigrConfig;
+use crate::snapshot::{Snapshot, SnapshotDiff, SnapshotEntry, SnapshotReader};
+use crate::stream::{BeeCellWriter, FileReader, STREAM_BUFFER_SIZE};
 use crate::user::User;
-use bcrypt::{hash, DEFAULT_COST};
+use bcrypt::{hash, verify, DEFAULT_COST};
 use std::error::Error as StdError;
 use std::fmt;
 use crate::seigrconfig::{KEY_LENGTH, NONCE_LENGTH};
@@ -22,8 +43,22 @@ pub enum DatabaseError {
     UserNotFound,
     AuthenticationFailed,
     LockFailed,
+    IntegrityViolation(String),
+    FileExists(String),
+    VersionNotFound(String, u64),
+    SnapshotExists(String),
+    SnapshotNotFound(String),
+    InvalidPath(String),
+    DirectoryNotFound(String),
+    DirectoryNotEmpty(String),
+    PermissionDenied(String),
+    QuotaExceeded(String, u64),
+    InvalidQuery(String),
+    InvalidArchive(String),
+    Cancelled,
 }
 
+/// A batch of changes that is applied to the database completely or not at all.
 pub struct Transaction {
     changes: Vec<Change>,
 }
@@ -31,7 +66,14 @@ pub struct Transaction {
 enum Change {
     StoreFile { filename: String, data: Vec<u8> },
     DeleteFile { filename: String },
-    // Add more variants here for other types of changes
+    RenameFile { from: String, to: String },
+    UpdateMetadata { filename: String, key: String, value: Option<String> },
+}
+
+impl Default for Transaction {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Transaction {
@@ -47,60 +89,516 @@ impl Transaction {
         self.changes.push(Change::DeleteFile { filename });
     }
 
-    pub fn commit(self, database: &mut Database) -> Result<(), DatabaseError> {
+    pub fn rename_file(&mut self, from: String, to: String) {
+        self.changes.push(Change::RenameFile { from, to });
+    }
+
+    /// Sets a metadata `key` of a file, or removes it when `value` is `None`.
+    pub fn update_metadata(&mut self, filename: String, key: String, value: Option<String>) {
+        self.changes.push(Change::UpdateMetadata { filename, key, value });
+    }
+
+    /// Applies every change, in order. If any of them fails the database is left untouched.
+    pub fn commit(self, database: &Database) -> Result<(), DatabaseError> {
+        let mut staging = database.start_staging();
+        // File contents go to the hive first, nothing refers to them until the ops are applied
+        let mut ops = Vec::with_capacity(self.changes.len());
         for change in self.changes {
-            match change {
-                Change::StoreFile { filename, data } => database.store_file(filename, data)?,
-                Change::DeleteFile { filename } => database.delete_file(filename)?,
-                // Handle other types of changes here
+            let op = match change {
+                Change::StoreFile { filename, data } => database.stage_data(filename, &data, &mut staging),
+                Change::DeleteFile { filename } => Database::catalog_name(&database.catalog(), &filename).map(|filename| CatalogOp::Unlink { filename }),
+                Change::RenameFile { from, to } => database.rename_op(from, &to),
+                Change::UpdateMetadata { filename, key, value } => {
+                    Database::catalog_name(&database.catalog(), &filename).map(|filename| CatalogOp::SetMetadata { filename, key, value })
+                }
+            };
+            match op {
+                Ok(op) => ops.push(op),
+                Err(error) => {
+                    database.discard_staged(staging);
+                    return Err(error);
+                }
             }
         }
-        Ok(())
+        database.commit_staged(ops, staging)
     }
+
+    /// Drops every change without applying any of them.
+    pub fn rollback(self) {}
 }
 
-#[derive(Debug, Clone)]
+// The payload of a beecell lives in the hive under its `hash`, this only records where to find it.
+// Compressed or encrypted beecells are stored with a header under the hash of their stored bytes
+// instead, kept in `object`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BeeCell {
-    id: String,
-    data: Vec<u8>,
-    hash: String,
-    previous_id: String,
-    next_id: String,
+    pub(crate) id: String,
+    pub(crate) hash: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub(crate) object: String,
+    #[serde(default)]
+    pub(crate) object_size: u64,
+    #[serde(default)]
+    pub(crate) size: u64,
+    // Where the beecell starts inside its frame
+    #[serde(default)]
+    pub(crate) offset: u64,
+    pub(crate) previous_id: String,
+    pub(crate) next_id: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Frame {
-    id: String,
-    beecells: Vec<BeeCell>,
-    previous_id: String,
-    next_id: String,
+    pub(crate) id: String,
+    pub(crate) beecells: Vec<BeeCell>,
+    pub(crate) previous_id: String,
+    pub(crate) next_id: String,
+    // Merkle root over the addresses of `beecells`
+    #[serde(default)]
+    pub(crate) root: String,
+    // Where the frame starts inside its cube, and how many bytes its beecells hold
+    #[serde(default)]
+    pub(crate) offset: u64,
+    #[serde(default)]
+    pub(crate) size: u64,
+    // Reed-Solomon parity over the beecells, when erasure coding was on
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) parity: Vec<ParityStripe>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Cube {
+    pub(crate) id: String,
+    pub(crate) frames: Vec<Frame>,
+    // Merkle root over the roots of `frames`
+    pub(crate) root: String,
+    pub(crate) size: u64,
+}
+
+// What `cubes/<cube_id>/cube.json` holds: the frames themselves get their own manifests
+#[derive(Debug, Serialize, Deserialize)]
+struct CubeManifest {
     id: String,
-    frames: Vec<Frame>,
+    frames: Vec<String>,
+    #[serde(default)]
+    root: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrubProblem {
+    Missing,
+    Corrupt,
+}
+
+/// A beecell that failed verification, and where it is used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrubIssue {
+    pub cube_id: String,
+    pub frame_id: String,
+    pub beecell_id: String,
+    pub hash: String,
+    pub problem: ScrubProblem,
+}
+
+/// Result of [`Database::repair`]: the damaged beecells and parity shards that were rebuilt, and
+/// the ones that couldn't be.
+#[derive(Debug, Default, Clone)]
+pub struct RepairReport {
+    pub repaired: Vec<ScrubIssue>,
+    pub unrecoverable: Vec<ScrubIssue>,
+}
+
+/// Result of [`Database::collect_garbage`] and [`Database::garbage_report`]: what is, or would
+/// be, reclaimed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub cubes: usize,
+    pub beecells: usize,
+    pub reclaimable_bytes: u64,
+}
+
+// Cubes and beecells in the hive that nothing points at, with their sizes
+#[derive(Debug, Default)]
+struct Garbage {
+    cubes: Vec<(String, u64)>,
+    beecells: Vec<(String, u64)>,
+}
+
+/// Result of [`Database::scrub`].
+#[derive(Debug, Default, Clone)]
+pub struct ScrubReport {
+    pub cubes_checked: usize,
+    pub beecells_checked: usize,
+    pub bytes_checked: u64,
+    pub issues: Vec<ScrubIssue>,
+    /// Files pointing at cubes that aren't in the hive, as `(filename, cube_id)`.
+    pub missing_cubes: Vec<(String, String)>,
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty() && self.missing_cubes.is_empty()
+    }
+}
+
+/// Space used by the hive: `logical_bytes` is what the files add up to, `physical_bytes` is what
+/// is actually stored once identical beecells are shared and compressed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StorageStats {
+    pub files: usize,
+    pub beecells: usize,
+    pub unique_beecells: usize,
+    pub logical_bytes: u64,
+    pub physical_bytes: u64,
+}
+
+/// A handle on a hive, safe to share between threads.
+///
+/// Readers work on the catalog as of the last commit and never wait for writers. Writers store
+/// their beecells side by side and only take turns to commit.
+///
+/// Every handle acts for whoever signed in on it, `sign_in` hands out a new one on the same hive
+/// and clones act for the same user.
+#[derive(Debug, Clone)]
 pub struct Database {
-    file_links: HashMap<String, Vec<String>>,
-    cubes: HashMap<String, Cube>,
-    users: HashMap<String, User>,
+    hive: Arc<Hive>,
+    // Files stored through this handle are owned by, and encrypted for, this user
+    username: Option<String>,
+}
+
+// What every handle on a hive shares
+#[derive(Debug)]
+struct Hive {
+    store: HiveStore,
+    // Replaced as a whole by every commit, so whoever holds on to one always sees it consistent
+    catalog: RwLock<Arc<Catalog>>,
+    // Cubes never change once saved, readers keep the ones they need
+    cubes: RwLock<HashMap<String, Arc<Cube>>>,
+    users: RwLock<HashMap<String, User>>,
+    // Quota of users that don't have their own, from the SeigrConfig
+    default_quota: Option<u64>,
+    // Where the SeigrConfig the users come from is kept, and its key, to save changes to them
+    config_path: PathBuf,
+    config_key: [u8; KEY_LENGTH],
+    // Commits are applied one at a time
+    commit_lock: Mutex<()>,
+    // How many stores are writing beecells that aren't linked yet, and scrubs are reading cubes.
+    // Nothing is deleted from the hive while there are any, it may be what they are about to
+    // link or read.
+    staging: Mutex<usize>,
+    staging_done: Condvar,
+    // Beecells released while stores were in flight, deleted by a later commit
+    released: Mutex<Vec<String>>,
+}
+
+// The catalog as one caller sees it, and who they are signed in as
+struct View {
+    catalog: Arc<Catalog>,
+    beeid: Option<String>,
+    admin: bool,
+}
+
+// Held by a store from its first beecell until its commit, and by a scrub, see `Database::staging`
+struct StagingGuard<'a> {
+    hive: &'a Hive,
+    // Addresses written by the store, deleted again if it fails
+    staged: Vec<String>,
+}
+
+impl Drop for StagingGuard<'_> {
+    fn drop(&mut self) {
+        let mut staging = lock(&self.hive.staging);
+        *staging -= 1;
+        if *staging == 0 {
+            self.hive.staging_done.notify_all();
+        }
+    }
 }
 
+const CONFIG_FILE_NAME: &str = "seigrconfig.toml";
+pub(crate) const CELLS_PER_FRAME: usize = 100;
+pub(crate) const FRAMES_PER_CUBE: usize = 16;
+const CUBE_MANIFEST_FILE: &str = "cube.json";
+
+/// A stored file. [`Database::stat`] fills in everything but the content.
 #[derive(Default, Debug, Clone)]
 pub struct File {
     pub filename: String,
+    /// Beeid of the owner, the user who first stored the file. Empty if nobody was signed in.
     pub beeid: String,
     pub data: Vec<u8>,
     pub previous_beecell_id: String,
     pub next_beecell_id: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch, 0 if the file was stored before this was recorded.
+    pub created: u64,
+    pub modified: u64,
+    pub mime_type: String,
+    /// Blake2b hash of the content, see [`FileVersion::content_hash`].
+    pub content_hash: String,
+    pub version: u64,
+    pub attributes: BTreeMap<String, String>,
+    pub tags: BTreeSet<String>,
+}
+
+impl BeeCell {
+    pub(crate) fn new(index: usize, hash: String, size: u64) -> Self {
+        BeeCell {
+            id: format!("beecell{}", index),
+            hash,
+            object: String::new(),
+            object_size: 0,
+            size,
+            offset: 0,
+            previous_id: if index > 0 { format!("beecell{}", index - 1) } else { String::new() },
+            next_id: format!("beecell{}", index + 1),
+        }
+    }
+
+    /// How many bytes the beecell takes up in the hive.
+    pub(crate) fn stored_size(&self) -> u64 {
+        if self.object.is_empty() {
+            self.size
+        } else {
+            self.object_size
+        }
+    }
+
+    /// Where the beecell is stored in the hive.
+    pub(crate) fn address(&self) -> &str {
+        if self.object.is_empty() {
+            &self.hash
+        } else {
+            &self.object
+        }
+    }
+}
+
+impl Frame {
+    // Everything the frame keeps in the hive, as `(id, address, stored size)`: the beecells of
+    // stripe `stripe` followed by its parity shards
+    fn stripe_objects(&self, stripe: usize) -> Vec<(String, &str, u64)> {
+        let parity = &self.parity[stripe];
+        let cells = self.beecells[parity.first..parity.first + parity.cells]
+            .iter()
+            .map(|beecell| (beecell.id.clone(), beecell.address(), beecell.stored_size()));
+        let shards = parity
+            .parity
+            .iter()
+            .enumerate()
+            .map(|(i, address)| (format!("parity{}.{}", stripe, i), address.as_str(), parity.shard_size));
+        cells.chain(shards).collect()
+    }
+
+    // Every beecell and parity shard of the frame, as `(id, address, stored size)`
+    pub(crate) fn objects(&self) -> Vec<(String, &str, u64)> {
+        let cells = self.beecells.iter().map(|beecell| (beecell.id.clone(), beecell.address(), beecell.stored_size()));
+        let shards = (0..self.parity.len()).flat_map(|stripe| self.stripe_objects(stripe).into_iter().skip(self.parity[stripe].cells));
+        cells.chain(shards).collect()
+    }
+}
+
+impl Cube {
+    /// Hive addresses of every beecell and parity shard in the cube.
+    pub(crate) fn addresses(&self) -> impl Iterator<Item = &str> {
+        self.frames.iter().flat_map(|frame| {
+            let cells = frame.beecells.iter().map(BeeCell::address);
+            cells.chain(frame.parity.iter().flat_map(|stripe| stripe.parity.iter().map(String::as_str)))
+        })
+    }
+}
+
+// Reads what is stored at `address`, or says why it can't be used
+fn load_object(store: &HiveStore, address: &str) -> io::Result<Result<Vec<u8>, ScrubProblem>> {
+    match store.read_beecell(address) {
+        Ok(data) if calculate_hash(&data) == address => Ok(Ok(data)),
+        Ok(_) => Ok(Err(ScrubProblem::Corrupt)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Err(ScrubProblem::Missing)),
+        Err(error) => Err(error),
+    }
+}
+
+/// Reads the stored bytes of `beecell` and checks they still match its address.
+pub(crate) fn read_verified_object(store: &HiveStore, beecell: &BeeCell) -> Result<Vec<u8>, DatabaseError> {
+    let address = beecell.address();
+    match load_object(store, address)? {
+        Ok(data) => Ok(data),
+        Err(ScrubProblem::Missing) => Err(DatabaseError::IntegrityViolation(format!("beecell {} ({}) is missing", beecell.id, address))),
+        Err(ScrubProblem::Corrupt) => Err(DatabaseError::IntegrityViolation(format!("beecell {} ({}) is corrupt", beecell.id, address))),
+    }
+}
+
+// Rebuilds the stored bytes of beecell `cell` from the parity of its frame. Returns `None` if the
+// frame has no parity for it.
+fn recover_object(store: &HiveStore, frame: &Frame, cell: usize) -> Result<Option<Vec<u8>>, DatabaseError> {
+    let Some(stripe) = frame.parity.iter().position(|stripe| (stripe.first..stripe.first + stripe.cells).contains(&cell)) else {
+        return Ok(None);
+    };
+    let objects = frame.stripe_objects(stripe);
+    let mut shards = objects
+        .iter()
+        .map(|(_, address, _)| load_object(store, address).map(Result::ok))
+        .collect::<io::Result<Vec<_>>>()?;
+    erasure::reconstruct(&frame.parity[stripe], &mut shards)?;
+
+    let index = cell - frame.parity[stripe].first;
+    let (_, address, size) = &objects[index];
+    let mut object = shards[index].take().unwrap_or_default();
+    object.truncate(*size as usize);
+    if calculate_hash(&object) != *address {
+        return Err(DatabaseError::IntegrityViolation(format!("beecell {} ({}) could not be rebuilt", objects[index].0, address)));
+    }
+    Ok(Some(object))
+}
+
+/// Reads the payload of beecell `cell` of `frame`, which is the beecell at `index` in its file.
+///
+/// A missing or corrupt beecell is rebuilt from the frame's parity when there is some. The payload
+/// is decrypted with the file's `key` and decompressed, and checked against its hash.
+pub(crate) fn read_frame_beecell(store: &HiveStore, frame: &Frame, cell: usize, key: Option<&DataKey>, index: usize) -> Result<Vec<u8>, DatabaseError> {
+    let beecell = &frame.beecells[cell];
+    let object = match read_verified_object(store, beecell) {
+        Err(DatabaseError::IntegrityViolation(message)) => recover_object(store, frame, cell)?.ok_or(DatabaseError::IntegrityViolation(message))?,
+        result => result?,
+    };
+    let data = if beecell.object.is_empty() {
+        object
+    } else {
+        decode_beecell(&object, beecell.size, key, CellPosition::of(index))?
+    };
+    if data.len() as u64 != beecell.size || calculate_hash(&data) != beecell.hash {
+        return Err(DatabaseError::IntegrityViolation(format!("beecell {} ({}) is corrupt", beecell.id, beecell.hash)));
+    }
+    Ok(data)
+}
+
+/// Reads the whole content held by `cubes`, in order.
+pub(crate) fn read_cubes<'a>(store: &HiveStore, cubes: impl IntoIterator<Item = &'a Cube>, key: Option<&DataKey>) -> Result<Vec<u8>, DatabaseError> {
+    let mut data = Vec::new();
+    let mut index = 0;
+    for frame in cubes.into_iter().flat_map(|cube| &cube.frames) {
+        for cell in 0..frame.beecells.len() {
+            data.extend(read_frame_beecell(store, frame, cell, key, index)?);
+            index += 1;
+        }
+    }
+    Ok(data)
+}
+
+// Seconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default()
+}
+
+// Whatever a lock guards is only ever replaced whole, so it is still usable after a thread
+// panicked holding the lock
+fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Read and write access can be handed out with the share permission, anything more needs admin
+fn required_to_grant(permission: Permission) -> Permission {
+    if permission > Permission::Write {
+        Permission::Admin
+    } else {
+        Permission::Share
+    }
+}
+
+// Tags have to survive being written into a query
+fn check_tag(tag: &str) -> Result<(), DatabaseError> {
+    if tag.is_empty() || tag.contains(|c: char| c.is_whitespace() || c == '"' || c == '(' || c == ')') {
+        return Err(DatabaseError::Other(format!("Invalid tag: {:?}", tag)));
+    }
+    Ok(())
+}
+
+// Everything the manifest of an archive lists, and everything its cubes point at, has to have
+// come along
+fn check_archive_is_complete(manifest: &ArchiveManifest, cubes: &[Cube], objects: &HashSet<String>) -> Result<(), DatabaseError> {
+    let archived: HashSet<&str> = cubes.iter().map(|cube| cube.id.as_str()).collect();
+    if let Some(cube_id) = manifest.cubes.iter().find(|cube_id| !archived.contains(cube_id.as_str())) {
+        return Err(DatabaseError::InvalidArchive(format!("cube {} is missing", cube_id)));
+    }
+    let mut addresses = manifest.objects.iter().map(String::as_str).chain(cubes.iter().flat_map(Cube::addresses));
+    match addresses.find(|address| !objects.contains(*address)) {
+        Some(address) => Err(DatabaseError::InvalidArchive(format!("object {} is missing", address))),
+        None => Ok(()),
+    }
+}
+
+// Sizes in a frame read from an archive are all that tell how much to allocate for its
+// beecells and parity shards, and which beecells each stripe protects
+fn check_archived_frame(frame: &Frame) -> Result<(), DatabaseError> {
+    let max_stored_size = (MAX_CHUNK_SIZE + MAX_ENCODING_OVERHEAD) as u64;
+    let invalid = |problem: &str| Err(DatabaseError::InvalidArchive(format!("frame {} {}", frame.id, problem)));
+    if frame.beecells.iter().any(|beecell| beecell.size > MAX_CHUNK_SIZE as u64 || beecell.stored_size() > max_stored_size) {
+        return invalid("has an oversized beecell");
+    }
+    for stripe in &frame.parity {
+        let end = stripe.first.checked_add(stripe.cells);
+        let cells = match end {
+            Some(end) if stripe.cells > 0 && end <= frame.beecells.len() => &frame.beecells[stripe.first..end],
+            _ => return invalid("has parity for beecells it doesn't hold"),
+        };
+        if stripe.parity.is_empty() || stripe.cells + stripe.parity.len() > erasure::MAX_SHARDS {
+            return invalid("has a parity stripe with too many shards");
+        }
+        if stripe.shard_size > max_stored_size || cells.iter().any(|beecell| beecell.stored_size() > stripe.shard_size) {
+            return invalid("has parity shards that don't fit its beecells");
+        }
+    }
+    Ok(())
+}
+
+// Cube and frame ids end up in paths inside the hive
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Hashes content the way [`FileVersion::content_hash`] is computed, to look it up with
+/// [`Database::find_by_hash`].
+pub fn content_digest<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut hasher = Blake2b::<U64>::default();
+    let mut buffer = vec![0u8; STREAM_BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => hasher.update(&buffer[..count]),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+pub(crate) fn calculate_hash(data: &[u8]) -> String {
+    let mut hasher = Blake2b::default();
+    hasher.update(data);
+    let result: generic_array::GenericArray<u8, U64> = hasher.finalize();
+
+    hex::encode(result.as_slice())
 }
 
 impl From<std::io::Error> for DatabaseError {
     fn from(error: std::io::Error) -> Self {
-        DatabaseError::IoError(error)
+        // Readers have to report a DatabaseError as an io::Error, unwrap it again here
+        if !error.get_ref().is_some_and(|inner| inner.is::<DatabaseError>()) {
+            return DatabaseError::IoError(error);
+        }
+        match error.into_inner().map(|inner| inner.downcast::<DatabaseError>()) {
+            Some(Ok(inner)) => *inner,
+            _ => DatabaseError::Other("Lost an error while unwrapping it".to_string()),
+        }
     }
 }
 
@@ -122,6 +620,19 @@ impl fmt::Display for DatabaseError {
             DatabaseError::UserNotFound => write!(f, "User not found"),
             DatabaseError::AuthenticationFailed => write!(f, "Authentication failed"),
             DatabaseError::LockFailed => write!(f, "Failed to acquire lock"),
+            DatabaseError::IntegrityViolation(message) => write!(f, "Integrity violation: {}", message),
+            DatabaseError::FileExists(filename) => write!(f, "File already exists: {}", filename),
+            DatabaseError::VersionNotFound(filename, version) => write!(f, "Version {} of {} not found", version, filename),
+            DatabaseError::SnapshotExists(name) => write!(f, "Snapshot already exists: {}", name),
+            DatabaseError::SnapshotNotFound(name) => write!(f, "Snapshot not found: {}", name),
+            DatabaseError::InvalidPath(path) => write!(f, "Invalid path: {}", path),
+            DatabaseError::DirectoryNotFound(path) => write!(f, "Directory not found: {}", path),
+            DatabaseError::DirectoryNotEmpty(path) => write!(f, "Directory not empty: {}", path),
+            DatabaseError::PermissionDenied(filename) => write!(f, "Permission denied: {}", filename),
+            DatabaseError::QuotaExceeded(beeid, quota) => write!(f, "Quota of {} bytes exceeded for {}", quota, beeid),
+            DatabaseError::InvalidQuery(message) => write!(f, "Invalid query: {}", message),
+            DatabaseError::InvalidArchive(message) => write!(f, "Invalid archive: {}", message),
+            DatabaseError::Cancelled => write!(f, "Operation cancelled"),
         }
     }
 }
@@ -138,7 +649,19 @@ impl fmt::Debug for DatabaseError {
             DatabaseError::UserNotFound => write!(f, "User not found"),
             DatabaseError::AuthenticationFailed => write!(f, "Authentication failed"),
             DatabaseError::LockFailed => write!(f, "Failed to acquire lock"),
-
+            DatabaseError::IntegrityViolation(message) => write!(f, "Integrity violation: {}", message),
+            DatabaseError::FileExists(filename) => write!(f, "File already exists: {}", filename),
+            DatabaseError::VersionNotFound(filename, version) => write!(f, "Version {} of {} not found", version, filename),
+            DatabaseError::SnapshotExists(name) => write!(f, "Snapshot already exists: {}", name),
+            DatabaseError::SnapshotNotFound(name) => write!(f, "Snapshot not found: {}", name),
+            DatabaseError::InvalidPath(path) => write!(f, "Invalid path: {}", path),
+            DatabaseError::DirectoryNotFound(path) => write!(f, "Directory not found: {}", path),
+            DatabaseError::DirectoryNotEmpty(path) => write!(f, "Directory not empty: {}", path),
+            DatabaseError::PermissionDenied(filename) => write!(f, "Permission denied: {}", filename),
+            DatabaseError::QuotaExceeded(beeid, quota) => write!(f, "Quota of {} bytes exceeded for {}", quota, beeid),
+            DatabaseError::InvalidQuery(message) => write!(f, "Invalid query: {}", message),
+            DatabaseError::InvalidArchive(message) => write!(f, "Invalid archive: {}", message),
+            DatabaseError::Cancelled => write!(f, "Operation cancelled"),
         }
     }
 }
@@ -153,6 +676,7 @@ impl File {
             data,
             previous_beecell_id,
             next_beecell_id,
+            ..File::default()
         }
     }
 }
@@ -177,67 +701,414 @@ impl PartialEq for File {
 
 impl Eq for File {}
 
+impl View {
+    // Checks that the signed-in user may do `permission` with a file. Files without an owner
+    // are open to everyone, as are files that don't exist.
+    fn check_access(&self, filename: &str, permission: Permission) -> Result<(), DatabaseError> {
+        if self.allows(self.catalog.acls.get(filename), permission) {
+            Ok(())
+        } else {
+            Err(DatabaseError::PermissionDenied(filename.to_string()))
+        }
+    }
+
+    // Checks that the signed-in user may create `path`, which needs write access to the directory
+    // it goes in. Missing directories above it are created along with it, so that is the closest
+    // one that exists.
+    fn check_create(&self, path: &str) -> Result<(), DatabaseError> {
+        let parent = parent_dirs(path).filter(|dir| self.catalog.directories.contains(*dir)).last().unwrap_or_default();
+        self.check_access(parent, Permission::Write)
+    }
+
+    fn allows(&self, acl: Option<&Acl>, permission: Permission) -> bool {
+        acl.is_none_or(|acl| acl.allows(self.beeid.as_deref(), &self.catalog.groups, permission))
+    }
+
+    // Every change to the catalog goes through here before it is applied
+    fn authorize(&self, op: &CatalogOp) -> Result<(), DatabaseError> {
+        match op {
+            CatalogOp::Link { filename, .. } | CatalogOp::Import { filename, .. } if !self.catalog.file_links.contains_key(filename) => self.check_create(filename),
+            CatalogOp::Link { filename, .. }
+            | CatalogOp::Restore { filename, .. }
+            | CatalogOp::Unlink { filename }
+            | CatalogOp::SetMetadata { filename, .. }
+            | CatalogOp::SetTag { filename, .. }
+            | CatalogOp::Import { filename, .. } => self.check_access(filename, Permission::Write),
+            CatalogOp::Rename { from, to } => {
+                // Moving a directory moves every file and directory in it
+                let mut moved = self.catalog.file_links.keys().chain(&self.catalog.directories).filter(|path| *path == from || is_within(path, from));
+                moved.try_for_each(|path| self.check_access(path, Permission::Write))?;
+                self.check_create(to)
+            }
+            CatalogOp::SetAcl { filename, .. } => self.check_access(filename, Permission::Share),
+            CatalogOp::GrantAcl { filename, principal, permission } => {
+                self.check_access(filename, Permission::Read)?;
+                // Taking away more than read or write needs admin as well
+                let previous = self.catalog.acls.get(filename).and_then(|acl| acl.granted(principal));
+                self.check_access(filename, required_to_grant(previous.map_or(*permission, |previous| previous.max(*permission))))
+            }
+            CatalogOp::RevokeAcl { filename, principal } => {
+                self.check_access(filename, Permission::Read)?;
+                match self.catalog.acls.get(filename).and_then(|acl| acl.granted(principal)) {
+                    Some(previous) => self.check_access(filename, required_to_grant(previous)),
+                    None => Ok(()),
+                }
+            }
+            CatalogOp::SetOwner { filename, .. } => self.check_access(filename, Permission::Admin),
+            CatalogOp::SetGroup { name: group, .. } | CatalogOp::AddMember { group, .. } | CatalogOp::RemoveMember { group, .. } => {
+                match (self.beeid.as_deref(), self.catalog.groups.get(group)) {
+                    (Some(beeid), Some(existing)) if existing.owner == beeid => Ok(()),
+                    (Some(_), None) => Ok(()),
+                    _ => Err(DatabaseError::PermissionDenied(group.clone())),
+                }
+            }
+            CatalogOp::CreateSnapshot { .. } => Ok(()),
+            // Snapshots taken while nobody was signed in belong to nobody, like files without an owner
+            CatalogOp::DeleteSnapshot { name } => match self.catalog.snapshots.get(name) {
+                Some(snapshot) if !self.admin && !snapshot.owner.is_empty() && self.beeid.as_deref() != Some(snapshot.owner.as_str()) => {
+                    Err(DatabaseError::PermissionDenied(name.clone()))
+                }
+                _ => Ok(()),
+            },
+            CatalogOp::MakeDirectory { path, .. } => self.check_create(path),
+            CatalogOp::RemoveDirectory { path } => {
+                self.check_access(parent_dir(path), Permission::Write)?;
+                self.check_access(path, Permission::Write)
+            }
+        }
+    }
+}
+
 impl Database {
+    /// Opens the hive stored in the `db_path` directory, creating it if needed.
+    ///
+    /// The users are loaded from the `seigrconfig.toml` kept inside the hive.
     pub fn new(db_path: &str, key: &[u8; KEY_LENGTH], nonce: &[u8; NONCE_LENGTH]) -> io::Result<Self> {
-        let config = SeigrConfig::new(db_path, &key, &nonce)?; // assuming `key` and `nonce` are defined in this scope
+        let root = Path::new(db_path);
+        let config_path = root.join(CONFIG_FILE_NAME);
+        let config = SeigrConfig::new(&config_path.to_string_lossy(), key, nonce)?;
 
-        Ok(Database {
-            cubes: HashMap::new(),
-            file_links: HashMap::new(),
-            users: config.users, // Load the users from the SeigrConfig
-        })
+        let store = HiveStore::open(root)?;
+        let mut catalog: Catalog = store.read_json(Path::new(CATALOG_FILE))?.unwrap_or_default();
+        let cubes = Self::load_cubes(&store)?;
+        catalog.migrate_versions(&cubes);
+        catalog.migrate_directories();
+        catalog.migrate_acls();
+        catalog.migrate_index();
+        catalog.build_content_index();
+        catalog.build_usage(&cubes);
+
+        let database = Database {
+            hive: Arc::new(Hive {
+                store,
+                catalog: RwLock::new(Arc::new(catalog)),
+                cubes: RwLock::new(cubes),
+                users: RwLock::new(config.users), // Load the users from the SeigrConfig
+                default_quota: config.default_quota,
+                config_path,
+                config_key: *key,
+                commit_lock: Mutex::new(()),
+                staging: Mutex::new(0),
+                staging_done: Condvar::new(),
+                released: Mutex::new(Vec::new()),
+            }),
+            username: None,
+        };
+        database.recover_journal()?;
+        Ok(database)
     }
 
-    fn calculate_hash(&self, data: &[u8]) -> String {
-        let mut hasher = Blake2b::default();
-        hasher.update(data);
-        let result: generic_array::GenericArray<u8, U64> = hasher.finalize();
+    /// Where the SeigrConfig the users are loaded from is kept, inside the hive.
+    pub fn config_path(&self) -> &Path {
+        &self.hive.config_path
+    }
 
-        hex::encode(result.as_slice())
+    // The catalog as of the last commit
+    fn catalog(&self) -> Arc<Catalog> {
+        read_lock(&self.hive.catalog).clone()
     }
 
-    fn split_into_beecells(&self, data: Vec<u8>) -> Vec<BeeCell> {
-        let mut beecells = Vec::new();
+    fn view(&self) -> View {
+        let user = self.current_user();
+        View {
+            catalog: self.catalog(),
+            admin: user.as_ref().is_some_and(|user| user.admin),
+            beeid: user.map(|user| user.beeid),
+        }
+    }
 
-        let chunk_size = 150_000_000; // 150MB in bytes
-        let chunks = data.chunks(chunk_size);
+    fn start_staging(&self) -> StagingGuard<'_> {
+        *lock(&self.hive.staging) += 1;
+        StagingGuard { hive: &self.hive, staged: Vec::new() }
+    }
 
-        for (i, chunk) in chunks.enumerate() {
-            let id = format!("beecell{}", i);
-            let hash = self.calculate_hash(&chunk);
-            let previous_id = if i > 0 { format!("beecell{}", i - 1) } else { String::new() };
-            let next_id = format!("beecell{}", i + 1);
+    // Finishes a transaction that was journaled but not applied when the last session ended
+    fn recover_journal(&self) -> io::Result<()> {
+        let entry: JournalEntry = match self.hive.store.read_json(Path::new(JOURNAL_FILE)) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return Ok(()),
+            // A journal that can't be read was never completely written, so never committed
+            Err(error) if error.kind() == io::ErrorKind::InvalidData => return self.hive.store.remove_json(Path::new(JOURNAL_FILE)),
+            Err(error) => return Err(error),
+        };
 
-            let beecell = BeeCell {
-                id,
-                data: chunk.to_vec(),
-                hash,
-                previous_id,
-                next_id,
+        let current = self.catalog();
+        if entry.txid > current.last_txid {
+            let mut catalog = (*current).clone();
+            let mut released = Vec::new();
+            for op in &entry.ops {
+                let op_released = catalog
+                    .apply(&read_lock(&self.hive.cubes), op)
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, format!("Cannot replay journal: {}", error)))?;
+                released.extend(op_released);
+            }
+            catalog.last_txid = entry.txid;
+            self.hive.store.write_json(Path::new(CATALOG_FILE), &catalog)?;
+            *write_lock(&self.hive.catalog) = Arc::new(catalog);
+            self.remove_unreferenced(released)?;
+        }
+        self.hive.store.remove_json(Path::new(JOURNAL_FILE))
+    }
+
+    fn commit_ops(&self, ops: Vec<CatalogOp>) -> Result<(), DatabaseError> {
+        let released = self.apply_ops(ops)?;
+        self.remove_unreferenced(released)?;
+        Ok(())
+    }
+
+    // Commits the ops of a store, whose beecells can be deleted again once they are linked or not
+    fn commit_staged(&self, ops: Vec<CatalogOp>, staging: StagingGuard) -> Result<(), DatabaseError> {
+        match self.apply_ops(ops) {
+            Ok(released) => {
+                drop(staging);
+                self.remove_unreferenced(released)?;
+                Ok(())
+            }
+            Err(error) => {
+                self.discard_staged(staging);
+                Err(error)
+            }
+        }
+    }
+
+    // Deletes what a store that failed has written, except what something else links by now
+    fn discard_staged(&self, mut staging: StagingGuard) {
+        let staged = std::mem::take(&mut staging.staged);
+        drop(staging);
+        // The error that stopped the store is the one to report, whatever is left is only garbage
+        let _ = self.remove_unreferenced(staged);
+    }
+
+    // Applies `ops` all together: they are checked against a copy of the catalog, written to the
+    // journal, and only then is the catalog replaced. Returns the addresses no longer referenced.
+    fn apply_ops(&self, ops: Vec<CatalogOp>) -> Result<Vec<String>, DatabaseError> {
+        let _commit = lock(&self.hive.commit_lock);
+        let view = self.view();
+        for op in &ops {
+            view.authorize(op)?;
+        }
+        let current = view.catalog;
+        let mut catalog = (*current).clone();
+        let mut released = Vec::new();
+        {
+            let cubes = read_lock(&self.hive.cubes);
+            for op in &ops {
+                released.extend(catalog.apply(&cubes, op)?);
+            }
+            // Only new data or a new owner can push anyone over their quota
+            if ops.iter().any(|op| matches!(op, CatalogOp::Link { .. } | CatalogOp::Restore { .. } | CatalogOp::SetAcl { .. } | CatalogOp::SetOwner { .. } | CatalogOp::Import { .. })) {
+                self.check_quotas(&current, &catalog)?;
+            }
+        }
+        catalog.last_txid += 1;
+
+        let entry = JournalEntry { txid: catalog.last_txid, ops };
+        self.hive.store.write_json(Path::new(JOURNAL_FILE), &entry)?;
+        self.hive.store.write_json(Path::new(CATALOG_FILE), &catalog)?;
+        *write_lock(&self.hive.catalog) = Arc::new(catalog);
+        self.hive.store.remove_json(Path::new(JOURNAL_FILE))?;
+        Ok(released)
+    }
+
+    // Refuses `updated` if it raises the usage of a user who then ends up over quota. Users
+    // already over quota can still delete.
+    fn check_quotas(&self, current: &Catalog, updated: &Catalog) -> Result<(), DatabaseError> {
+        let users = read_lock(&self.hive.users);
+        if self.hive.default_quota.is_none() && users.values().all(|user| user.quota.is_none()) {
+            return Ok(());
+        }
+        let before = current.usage.reports();
+        for (beeid, usage) in updated.usage.reports() {
+            let previous = before.get(beeid).map_or(0, |usage| usage.stored_bytes);
+            match self.quota_of(&users, beeid) {
+                Some(quota) if usage.stored_bytes > previous && usage.stored_bytes > quota => {
+                    return Err(DatabaseError::QuotaExceeded(beeid.clone(), quota));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn quota_of(&self, users: &HashMap<String, User>, beeid: &str) -> Option<u64> {
+        let user = users.values().find(|user| user.beeid == beeid)?;
+        user.quota.or(self.hive.default_quota)
+    }
+
+    /// Sets the most bytes a user may store, or `None` for the hive's default quota. Only admins
+    /// can, and the quota is saved with the user in the hive's SeigrConfig.
+    pub fn set_quota(&self, username: &str, quota: Option<u64>) -> Result<(), DatabaseError> {
+        if !self.current_user().is_some_and(|user| user.admin) {
+            return Err(DatabaseError::PermissionDenied(username.to_string()));
+        }
+        self.update_user(username, |user| user.quota = quota)
+    }
+
+    // Changes a user and saves them to the SeigrConfig they were loaded from. Other changes to
+    // users wait, so that none of them is lost.
+    fn update_user(&self, username: &str, update: impl FnOnce(&mut User)) -> Result<(), DatabaseError> {
+        let mut users = write_lock(&self.hive.users);
+        let mut user = users.get(username).cloned().ok_or(DatabaseError::UserNotFound)?;
+        update(&mut user);
+        // The config holds more than the users, so it is read back rather than written over
+        let mut config = SeigrConfig::read_config_from(&self.hive.config_path, &self.hive.config_key)?;
+        config.users.insert(username.to_string(), user.clone());
+        config.save_config_to(&self.hive.config_path, &self.hive.config_key)?;
+        users.insert(username.to_string(), user);
+        Ok(())
+    }
+
+    /// How much every user owning a file stores, sorted by beeid.
+    pub fn usage(&self) -> Vec<UsageReport> {
+        let mut reports: Vec<UsageReport> = self.catalog().usage.reports().values().cloned().collect();
+        let users = read_lock(&self.hive.users);
+        for report in &mut reports {
+            report.quota = self.quota_of(&users, &report.beeid);
+        }
+        reports
+    }
+
+    /// How much one user stores, an empty report if they own nothing.
+    pub fn user_usage(&self, beeid: &str) -> UsageReport {
+        let mut report = self.catalog().usage.reports().get(beeid).cloned().unwrap_or_else(|| UsageReport {
+            beeid: beeid.to_string(),
+            ..UsageReport::default()
+        });
+        report.quota = self.quota_of(&read_lock(&self.hive.users), beeid);
+        report
+    }
+
+    fn load_cubes(store: &HiveStore) -> io::Result<HashMap<String, Arc<Cube>>> {
+        let mut cubes = HashMap::new();
+        for cube_id in store.cube_ids()? {
+            let cube_dir = PathBuf::from(CUBES_DIR).join(&cube_id);
+            let manifest: CubeManifest = match store.read_json(&cube_dir.join(CUBE_MANIFEST_FILE))? {
+                Some(manifest) => manifest,
+                // A cube without a manifest was never completely written
+                None => continue,
             };
 
-            beecells.push(beecell);
+            let mut frames = Vec::with_capacity(manifest.frames.len());
+            for frame_id in &manifest.frames {
+                let frame_path = cube_dir.join(format!("{}.json", frame_id));
+                let frame: Frame = store.read_json(&frame_path)?.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("Frame {} of cube {} is missing", frame_id, cube_id))
+                })?;
+                frames.push(frame);
+            }
+
+            // Cubes written before frames had roots get them computed on the fly
+            for frame in &mut frames {
+                if frame.root.is_empty() {
+                    frame.root = Self::frame_root(&frame.beecells);
+                }
+            }
+            let root = if manifest.root.is_empty() { Self::cube_root(&frames) } else { manifest.root };
+            let size = Self::index_frames(&mut frames);
+            cubes.insert(manifest.id.clone(), Arc::new(Cube { id: manifest.id, frames, root, size }));
+        }
+        Ok(cubes)
+    }
+
+    fn save_cube(&self, cube: &Cube) -> io::Result<()> {
+        let cube_dir = PathBuf::from(CUBES_DIR).join(&cube.id);
+        // Frames go first so a cube manifest never points at frames that aren't there
+        for frame in &cube.frames {
+            self.hive.store.write_json(&cube_dir.join(format!("{}.json", frame.id)), frame)?;
         }
+        let manifest = CubeManifest {
+            id: cube.id.clone(),
+            frames: cube.frames.iter().map(|frame| frame.id.clone()).collect(),
+            root: cube.root.clone(),
+        };
+        self.hive.store.write_json(&cube_dir.join(CUBE_MANIFEST_FILE), &manifest)
+    }
 
-        beecells
+    // Changes a setting kept in the catalog. Settings aren't journaled, they only apply to what
+    // is stored next.
+    fn update_settings(&self, update: impl FnOnce(&mut Catalog)) -> io::Result<()> {
+        let _commit = lock(&self.hive.commit_lock);
+        let mut catalog = (*self.catalog()).clone();
+        update(&mut catalog);
+        self.hive.store.write_json(Path::new(CATALOG_FILE), &catalog)?;
+        *write_lock(&self.hive.catalog) = Arc::new(catalog);
+        Ok(())
+    }
+
+    pub fn chunker_config(&self) -> ChunkerConfig {
+        self.catalog().chunker
+    }
+
+    /// Changes how new files are split into beecells. Files already stored keep their beecells.
+    pub fn set_chunker_config(&self, config: ChunkerConfig) -> Result<(), DatabaseError> {
+        config.validate().map_err(DatabaseError::Other)?;
+        self.update_settings(|catalog| catalog.chunker = config)?;
+        Ok(())
+    }
+
+    pub fn compression_policy(&self) -> CompressionPolicy {
+        self.catalog().compression.clone()
+    }
+
+    /// Changes how new beecells are compressed. Beecells already stored keep their codec.
+    pub fn set_compression_policy(&self, policy: CompressionPolicy) -> Result<(), DatabaseError> {
+        self.update_settings(|catalog| catalog.compression = policy)?;
+        Ok(())
+    }
+
+    pub fn erasure_config(&self) -> Option<ErasureConfig> {
+        self.catalog().erasure
+    }
+
+    /// Turns erasure coding of new cubes on or off. Cubes already stored keep their parity.
+    pub fn set_erasure_config(&self, config: Option<ErasureConfig>) -> Result<(), DatabaseError> {
+        if let Some(config) = config {
+            config.validate().map_err(DatabaseError::Other)?;
+        }
+        self.update_settings(|catalog| catalog.erasure = config)?;
+        Ok(())
     }
 
     fn group_into_frames(&self, beecells: Vec<BeeCell>) -> Vec<Frame> {
         let mut frames = Vec::new();
 
-        let cells_per_frame = 100;
-        let chunks = beecells.chunks(cells_per_frame);
+        let chunks = beecells.chunks(CELLS_PER_FRAME);
+        let frame_count = chunks.len();
 
         for (i, chunk) in chunks.enumerate() {
             let id = format!("frame{}", i);
             let previous_id = if i > 0 { format!("frame{}", i - 1) } else { String::new() };
-            let next_id = format!("frame{}", i + 1);
+            let next_id = if i + 1 < frame_count { format!("frame{}", i + 1) } else { String::new() };
 
             let frame = Frame {
                 id,
+                root: Self::frame_root(chunk),
                 beecells: chunk.to_vec(),
                 previous_id,
                 next_id,
+                offset: 0,
+                size: 0,
+                parity: Vec::new(),
             };
 
             frames.push(frame);
@@ -246,104 +1117,973 @@ impl Database {
         frames
     }
 
-    fn group_into_cube(&self, frames: Vec<Frame>) -> Cube {
-        let id = format!("cube{}", 0); // Assuming one cube for simplicity
-
-        let cube = Cube {
-            id,
-            frames,
-        };
-
-        cube
-    }
-
-    pub fn store_file(&mut self, filename: String, data: Vec<u8>) -> std::io::Result<()> {
-        let beecells = self.split_into_beecells(data);
-        let frames = self.group_into_frames(beecells);
-        let cube = self.group_into_cube(frames);
-    
-        // Store the cube in the cubes HashMap
-        let cube_id = cube.id.clone();
-        self.cubes.insert(cube_id.clone(), cube);
-    
-        // Store the file link
-        self.file_links.insert(filename, vec![cube_id]);
-    
-        Ok(())
+    // A file larger than one cube spans as many cubes as it needs, in order
+    fn group_into_cubes(&self, beecells: Vec<BeeCell>) -> Vec<Cube> {
+        beecells
+            .chunks(CELLS_PER_FRAME * FRAMES_PER_CUBE)
+            .map(|chunk| {
+                let mut frames = self.group_into_frames(chunk.to_vec());
+                let size = Self::index_frames(&mut frames);
+                // Cubes are named after their root, so the same id always means the same beecells
+                let root = Self::cube_root(&frames);
+                Cube {
+                    id: root.clone(),
+                    frames,
+                    root,
+                    size,
+                }
+            })
+            .collect()
     }
 
-    pub fn retrieve_file(&self, filename: &str) -> Result<Vec<u8>, DatabaseError> {
-        let cube_ids = self.file_links.get(filename).ok_or(DatabaseError::FileNotFound(filename.to_string()))?;
-    
-        let mut data = Vec::new();
-        for cube_id in cube_ids {
-            let cube = self.cubes.get(cube_id).ok_or(DatabaseError::CubeNotFound(cube_id.to_string()))?;
-            for frame in &cube.frames {
-                for beecell in &frame.beecells {
-                    data.extend(&beecell.data);
-                }
+    // Fills in the offsets that let a byte range be found without reading any beecell,
+    // returns the size of the whole cube
+    fn index_frames(frames: &mut [Frame]) -> u64 {
+        let mut frame_offset = 0;
+        for frame in frames {
+            let mut cell_offset = 0;
+            for beecell in &mut frame.beecells {
+                beecell.offset = cell_offset;
+                cell_offset += beecell.size;
             }
+            frame.offset = frame_offset;
+            frame.size = cell_offset;
+            frame_offset += cell_offset;
         }
-    
-        Ok(data)
+        frame_offset
     }
 
-    pub fn get_file_links(&self, filename: &str) -> Option<&Vec<String>> {
-        self.file_links.get(filename)
+    fn frame_root(beecells: &[BeeCell]) -> String {
+        let hashes: Vec<String> = beecells.iter().map(|beecell| beecell.address().to_string()).collect();
+        merkle_root(&hashes)
     }
 
-    pub fn slice_file(&self, filename: &str, start: usize, end: usize) -> Result<Vec<u8>, DatabaseError> {
-        let file_links = self.get_file_links(filename).ok_or(DatabaseError::FileNotFound(filename.to_string()))?;
-    
-        let mut sliced_file = Vec::new();
-    
-        // Add previous cells
-        for link in file_links.iter().take(start) {
-            let cell = self.retrieve_file(link)?;
-            sliced_file.extend(cell);
-        }
-    
-        // Add current cell
-        let cell = self.retrieve_file(filename)?;
-        sliced_file.extend(cell);
-    
-        // Add next cells
-        for link in file_links.iter().skip(end) {
-            let cell = self.retrieve_file(link)?;
-            sliced_file.extend(cell);
-        }
-    
-        Ok(sliced_file)
+    fn cube_root(frames: &[Frame]) -> String {
+        let roots: Vec<String> = frames.iter().map(|frame| frame.root.clone()).collect();
+        merkle_root(&roots)
     }
 
-    pub fn previous_beecell_id(beecell_id: &str) -> String {
-        let mut hasher = DefaultHasher::new();
-        beecell_id.hash(&mut hasher);
-        hasher.finish().to_string()
+    // The cubes of the current version of a file, with the key they are encrypted with if any,
+    // both from the same catalog
+    fn file_cubes(&self, filename: &str) -> Result<(Vec<Arc<Cube>>, Option<WrappedKey>), DatabaseError> {
+        let view = self.view();
+        let filename = Self::catalog_name(&view.catalog, filename)?;
+        view.check_access(&filename, Permission::Read)?;
+        let cube_ids = view.catalog.file_links.get(&filename).ok_or(DatabaseError::FileNotFound(filename.clone()))?;
+        Ok((self.cubes_of(cube_ids)?, view.catalog.keys.get(&filename).cloned()))
     }
 
-    pub fn next_beecell_id(beecell_id: &str) -> String {
-        let mut hasher = DefaultHasher::new();
-        beecell_id.hash(&mut hasher);
-        hasher.finish().to_string()
+    // The name `filename` is kept under in `catalog`. Files linked before paths were normalized
+    // are still found under their old name.
+    fn catalog_name(catalog: &Catalog, filename: &str) -> Result<String, DatabaseError> {
+        if catalog.file_links.contains_key(filename) {
+            return Ok(filename.to_string());
+        }
+        normalize_filename(filename)
     }
 
-    pub fn beecell_id(data: &[u8]) -> String {
-        let mut hasher = DefaultHasher::new();
-        data.hash(&mut hasher);
-        hasher.finish().to_string()
+    fn cubes_of(&self, cube_ids: &[String]) -> Result<Vec<Arc<Cube>>, DatabaseError> {
+        let cubes = read_lock(&self.hive.cubes);
+        cube_ids
+            .iter()
+            .map(|cube_id| cubes.get(cube_id).cloned().ok_or(DatabaseError::CubeNotFound(cube_id.to_string())))
+            .collect()
     }
 
-    pub fn beecell_id_from_previous(previous_beecell_id: &str, data: &[u8]) -> String {
-        let mut hasher = DefaultHasher::new();
-        previous_beecell_id.hash(&mut hasher);
-        data.hash(&mut hasher);
-        hasher.finish().to_string()
+    /// Merkle root over the cube roots of a file, what [`InclusionProof::verify`] checks against.
+    pub fn file_root(&self, filename: &str) -> Result<String, DatabaseError> {
+        let roots: Vec<String> = self.file_cubes(filename)?.0.iter().map(|cube| cube.root.clone()).collect();
+        Ok(merkle_root(&roots))
     }
 
-    pub fn beecell_id_from_next(next_beecell_id: &str, data: &[u8]) -> String {
-        let mut hasher = DefaultHasher::new();
-        next_beecell_id.hash(&mut hasher);
+    /// Proves that the beecell at `index` (counting from the start of the file) belongs to `filename`.
+    ///
+    /// The proof covers the beecell as stored, so for an encrypted file it is the sealed bytes
+    /// that verify against it.
+    pub fn prove_beecell(&self, filename: &str, index: usize) -> Result<InclusionProof, DatabaseError> {
+        let (cubes, _) = self.file_cubes(filename)?;
+        let cube_roots: Vec<String> = cubes.iter().map(|cube| cube.root.clone()).collect();
+
+        let mut remaining = index;
+        for (cube_index, cube) in cubes.iter().enumerate() {
+            for (frame_index, frame) in cube.frames.iter().enumerate() {
+                if remaining >= frame.beecells.len() {
+                    remaining -= frame.beecells.len();
+                    continue;
+                }
+
+                let beecell_hashes: Vec<String> = frame.beecells.iter().map(|beecell| beecell.address().to_string()).collect();
+                let frame_roots: Vec<String> = cube.frames.iter().map(|frame| frame.root.clone()).collect();
+                // The indexes are in range, so every proof exists
+                return Ok(InclusionProof {
+                    beecell_hash: beecell_hashes[remaining].clone(),
+                    frame_proof: merkle_proof(&beecell_hashes, remaining).unwrap_or_default(),
+                    frame_root: frame.root.clone(),
+                    cube_proof: merkle_proof(&frame_roots, frame_index).unwrap_or_default(),
+                    cube_root: cube.root.clone(),
+                    file_proof: merkle_proof(&cube_roots, cube_index).unwrap_or_default(),
+                });
+            }
+        }
+
+        Err(DatabaseError::Other(format!("File {} has no beecell {}", filename, index)))
+    }
+
+    pub fn store_file(&self, filename: String, data: Vec<u8>) -> Result<(), DatabaseError> {
+        let mut staging = self.start_staging();
+        match self.stage_data(filename, &data, &mut staging) {
+            Ok(op) => self.commit_staged(vec![op], staging),
+            Err(error) => {
+                self.discard_staged(staging);
+                Err(error)
+            }
+        }
+    }
+
+    // Writes `data` to the hive, returning the op that links it to `filename`
+    fn stage_data(&self, filename: String, data: &[u8], staging: &mut StagingGuard) -> Result<CatalogOp, DatabaseError> {
+        let (mut writer, key) = self.beecell_writer(&filename)?;
+        if let Err(error) = writer.write(data) {
+            staging.staged.extend(writer.addresses().map(str::to_string));
+            return Err(error.into());
+        }
+        self.stage_link(filename, writer, key, staging)
+    }
+
+    /// Stores everything `reader` yields under `filename`, holding at most one beecell in memory.
+    ///
+    /// If `reader` fails nothing is stored, and the beecells written so far are deleted again.
+    pub fn store_reader<R: Read>(&self, filename: String, reader: R) -> Result<(), DatabaseError> {
+        let mut staging = self.start_staging();
+        match self.stage_reader(filename, reader, &mut staging) {
+            Ok(op) => self.commit_staged(vec![op], staging),
+            Err(error) => {
+                self.discard_staged(staging);
+                Err(error)
+            }
+        }
+    }
+
+    // Writes everything `reader` yields to the hive, returning the op that links it to `filename`
+    fn stage_reader<R: Read>(&self, filename: String, mut reader: R, staging: &mut StagingGuard) -> Result<CatalogOp, DatabaseError> {
+        let (mut writer, key) = self.beecell_writer(&filename)?;
+        let mut buffer = vec![0u8; STREAM_BUFFER_SIZE];
+        loop {
+            let written = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(count) => writer.write(&buffer[..count]),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => Err(error),
+            };
+            if let Err(error) = written {
+                staging.staged.extend(writer.addresses().map(str::to_string));
+                return Err(error.into());
+            }
+        }
+        self.stage_link(filename, writer, key, staging)
+    }
+
+    /// Async version of [`Database::store_reader`]. The content is stored on tokio's blocking
+    /// pool, see [`AsyncDatabase::store_reader`].
+    pub async fn store_async_reader<R: AsyncRead + Unpin>(&self, filename: String, reader: R) -> Result<(), DatabaseError> {
+        AsyncDatabase::new(Arc::new(self.clone())).store_reader(filename, reader).await
+    }
+
+    /// Opens a stored file for reading. Beecells are loaded one at a time as the reader moves.
+    pub fn open_reader(&self, filename: &str) -> Result<FileReader, DatabaseError> {
+        let (cubes, key) = self.file_cubes(filename)?;
+        let key = self.unwrap_key(key.as_ref())?;
+        Ok(FileReader::new(self.hive.store.clone(), key, cubes.iter().flat_map(|cube| &cube.frames)))
+    }
+
+    /// Streams a stored file into `writer`, returning the number of bytes written. The file is
+    /// read on tokio's blocking pool, see [`AsyncDatabase::retrieve_to_writer`].
+    pub async fn retrieve_to_async_writer<W: AsyncWrite + Unpin>(&self, filename: &str, writer: W) -> Result<u64, DatabaseError> {
+        AsyncDatabase::new(Arc::new(self.clone())).retrieve_to_writer(filename, writer).await
+    }
+
+    // Starts writing a new file, compressed as the policy says for `filename`. While a user is
+    // signed in it gets a fresh data key, wrapped for that user.
+    fn beecell_writer(&self, filename: &str) -> Result<(BeeCellWriter, Option<WrappedKey>), DatabaseError> {
+        // Don't write any beecells for a store that would be refused anyway
+        let view = self.view();
+        view.check_access(&normalize_filename(filename)?, Permission::Write)?;
+        let (key, wrapped) = match self.current_user() {
+            Some(owner) => {
+                let key = DataKey::generate()?;
+                let wrapped = key.wrap(&owner)?;
+                (Some(key), Some(wrapped))
+            }
+            None => (None, None),
+        };
+        let policy = &view.catalog.compression;
+        let chunker = Chunker::new(view.catalog.chunker);
+        let writer = BeeCellWriter::new(self.hive.store.clone(), chunker, policy.codec_for(filename), policy.level, key);
+        Ok((writer, wrapped))
+    }
+
+    // Groups the beecells of a finished writer into cubes, saves the cubes that are new and
+    // returns the op that links them to `filename`. What it writes is noted in `staging`.
+    fn stage_link(&self, filename: String, writer: BeeCellWriter, key: Option<WrappedKey>, staging: &mut StagingGuard) -> Result<CatalogOp, DatabaseError> {
+        let filename = normalize_filename(&filename)?;
+        let (beecells, content_hash) = writer.finish()?;
+        staging.staged.extend(beecells.iter().map(|beecell| beecell.address().to_string()));
+        let cubes = self.group_into_cubes(beecells);
+        let cube_ids = cubes.iter().map(|cube| cube.id.clone()).collect();
+        let erasure = self.catalog().erasure;
+        for mut cube in cubes {
+            // An identical cube is already in the hive, there is nothing to write
+            if read_lock(&self.hive.cubes).contains_key(&cube.id) {
+                continue;
+            }
+            if let Some(config) = erasure {
+                for frame in &mut cube.frames {
+                    frame.parity = self.frame_parity(frame, config)?;
+                    staging.staged.extend(frame.parity.iter().flat_map(|stripe| stripe.parity.iter().cloned()));
+                }
+            }
+            // Another store may be saving the same cube, they write the same files
+            self.save_cube(&cube)?;
+            write_lock(&self.hive.cubes).insert(cube.id.clone(), Arc::new(cube));
+        }
+        Ok(CatalogOp::Link {
+            filename,
+            cube_ids,
+            key,
+            timestamp: now(),
+            author: self.author(),
+            content_hash,
+        })
+    }
+
+    // Beeid recorded as the author of new versions
+    fn author(&self) -> String {
+        self.current_user().map(|user| user.beeid).unwrap_or_default()
+    }
+
+    // Computes and stores the parity of every `data_shards` beecells of `frame`
+    fn frame_parity(&self, frame: &Frame, config: ErasureConfig) -> Result<Vec<ParityStripe>, DatabaseError> {
+        let mut stripes = Vec::new();
+        for (stripe, beecells) in frame.beecells.chunks(config.data_shards).enumerate() {
+            let data = beecells
+                .iter()
+                .map(|beecell| read_verified_object(&self.hive.store, beecell))
+                .collect::<Result<Vec<_>, _>>()?;
+            let (shard_size, shards) = compute_parity(&data, config.parity_shards)?;
+
+            let mut parity = Vec::new();
+            for shard in shards {
+                let address = calculate_hash(&shard);
+                self.hive.store.write_beecell(&address, &shard)?;
+                parity.push(address);
+            }
+            stripes.push(ParityStripe {
+                first: stripe * config.data_shards,
+                cells: beecells.len(),
+                shard_size,
+                parity,
+            });
+        }
+        Ok(stripes)
+    }
+
+    // Unwraps the data key of an encrypted file with the key of its owner
+    fn unwrap_key(&self, wrapped: Option<&WrappedKey>) -> Result<Option<DataKey>, DatabaseError> {
+        let Some(wrapped) = wrapped else {
+            return Ok(None);
+        };
+        let users = read_lock(&self.hive.users);
+        let owner = users.values().find(|user| user.beeid == wrapped.owner).ok_or(DatabaseError::UserNotFound)?;
+        wrapped.unwrap(owner).map(Some)
+    }
+
+    // Deletes the beecells no file refers to anymore, and the cubes nothing reaches. While stores
+    // are in flight they are only set aside, one of those stores may be about to link them again.
+    fn remove_unreferenced(&self, addresses: Vec<String>) -> io::Result<()> {
+        let mut released = lock(&self.hive.released);
+        released.extend(addresses);
+        let staging = match self.hive.staging.try_lock() {
+            Ok(staging) => staging,
+            Err(TryLockError::Poisoned(error)) => error.into_inner(),
+            Err(TryLockError::WouldBlock) => return Ok(()),
+        };
+        if *staging > 0 {
+            return Ok(());
+        }
+        // The staging lock is held until they are gone, so no store can start writing them again
+        let catalog = self.catalog();
+        for address in released.drain(..) {
+            // The same content may have been stored again in the meantime
+            if !catalog.refcounts.contains_key(&address) {
+                self.hive.store.remove_beecell(&address)?;
+            }
+        }
+        // A cube of a deleted file has lost beecells with it, so it goes too. Storing the same
+        // content again saves it anew, with its parity.
+        let reachable = catalog.reachable_cube_ids();
+        let unreachable: Vec<String> = read_lock(&self.hive.cubes).keys().filter(|cube_id| !reachable.contains(cube_id.as_str())).cloned().collect();
+        for cube_id in unreachable {
+            self.hive.store.remove_cube(&cube_id)?;
+            write_lock(&self.hive.cubes).remove(&cube_id);
+        }
+        Ok(())
+    }
+
+    /// Reports logical vs. physical bytes for every file in the hive.
+    pub fn stats(&self) -> Result<StorageStats, DatabaseError> {
+        let mut stats = StorageStats::default();
+        let mut seen = std::collections::HashSet::new();
+        let mut seen_objects = std::collections::HashSet::new();
+
+        let catalog = self.catalog();
+        let cubes = read_lock(&self.hive.cubes);
+        for cube_ids in catalog.file_links.values() {
+            stats.files += 1;
+            for cube_id in cube_ids {
+                let cube = cubes.get(cube_id).ok_or(DatabaseError::CubeNotFound(cube_id.to_string()))?;
+                for frame in &cube.frames {
+                    for beecell in &frame.beecells {
+                        stats.beecells += 1;
+                        stats.logical_bytes += beecell.size;
+                        if seen.insert(beecell.address()) {
+                            stats.unique_beecells += 1;
+                        }
+                    }
+                }
+                for (_, address, size) in cube.frames.iter().flat_map(Frame::objects) {
+                    if seen_objects.insert(address) {
+                        stats.physical_bytes += size;
+                    }
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+
+    pub fn retrieve_file(&self, filename: &str) -> Result<Vec<u8>, DatabaseError> {
+        let (cubes, key) = self.file_cubes(filename)?;
+        let key = self.unwrap_key(key.as_ref())?;
+        read_cubes(&self.hive.store, cubes.iter().map(Arc::as_ref), key.as_ref())
+    }
+
+    /// Returns everything known about a file except its content.
+    pub fn stat(&self, filename: &str) -> Result<File, DatabaseError> {
+        let view = self.view();
+        let filename = Self::catalog_name(&view.catalog, filename)?;
+        view.check_access(&filename, Permission::Read)?;
+        let versions = view.catalog.versions.get(&filename).ok_or(DatabaseError::FileNotFound(filename.clone()))?;
+        Self::file_info(&view.catalog, &filename, versions).ok_or(DatabaseError::FileNotFound(filename.clone()))
+    }
+
+    fn file_info(catalog: &Catalog, filename: &str, versions: &[FileVersion]) -> Option<File> {
+        let (first, current) = (versions.first()?, versions.last()?);
+        Some(File {
+            filename: filename.to_string(),
+            beeid: catalog.acls.get(filename).map(|acl| acl.owner.clone()).unwrap_or_default(),
+            size: current.size,
+            created: first.timestamp,
+            modified: current.timestamp,
+            mime_type: mime_guess::from_path(filename).first_or_octet_stream().essence_str().to_string(),
+            content_hash: current.content_hash.clone(),
+            version: current.version,
+            attributes: catalog.metadata.get(filename).cloned().unwrap_or_default(),
+            tags: catalog.tags.get(filename).cloned().unwrap_or_default(),
+            ..File::default()
+        })
+    }
+
+    /// Lists the files matching `options` that the signed-in user can read, one page at a time.
+    pub fn list_files(&self, options: &ListOptions) -> Result<FileListing, DatabaseError> {
+        let matcher = FileMatcher::new(options)?;
+        let view = self.view();
+        let files = view
+            .catalog
+            .versions
+            .iter()
+            .filter(|(filename, _)| matcher.matches(filename) && view.allows(view.catalog.acls.get(*filename), Permission::Read))
+            .filter_map(|(filename, versions)| Self::file_info(&view.catalog, filename, versions))
+            .collect();
+        paginate(files, options)
+    }
+
+    /// Every file and version whose content hashes to `digest`, see [`content_digest`]. Lets a
+    /// client check whether something is already in the hive before sending it.
+    pub fn find_by_hash(&self, digest: &str) -> Vec<(String, u64)> {
+        let digest = digest.to_ascii_lowercase();
+        let view = self.view();
+        let entries = view.catalog.content_index.get(&digest).into_iter().flatten();
+        entries
+            .filter(|(filename, _)| view.allows(view.catalog.acls.get(filename), Permission::Read))
+            .cloned()
+            .collect()
+    }
+
+    /// Lists every version of a file, oldest first. The last one is the current content.
+    pub fn list_versions(&self, filename: &str) -> Result<Vec<FileVersion>, DatabaseError> {
+        let view = self.view();
+        let filename = Self::catalog_name(&view.catalog, filename)?;
+        view.check_access(&filename, Permission::Read)?;
+        view.catalog.versions.get(&filename).cloned().ok_or(DatabaseError::FileNotFound(filename))
+    }
+
+    fn find_version(&self, filename: &str, version: u64) -> Result<FileVersion, DatabaseError> {
+        let view = self.view();
+        let filename = Self::catalog_name(&view.catalog, filename)?;
+        view.check_access(&filename, Permission::Read)?;
+        let versions = view.catalog.versions.get(&filename).ok_or(DatabaseError::FileNotFound(filename.clone()))?;
+        versions
+            .iter()
+            .find(|candidate| candidate.version == version)
+            .cloned()
+            .ok_or(DatabaseError::VersionNotFound(filename.clone(), version))
+    }
+
+    /// Returns a file as it was in `version`.
+    pub fn retrieve_version(&self, filename: &str, version: u64) -> Result<Vec<u8>, DatabaseError> {
+        let version = self.find_version(filename, version)?;
+        let cubes = self.cubes_of(&version.cube_ids)?;
+        let key = self.unwrap_key(version.key.as_ref())?;
+        read_cubes(&self.hive.store, cubes.iter().map(Arc::as_ref), key.as_ref())
+    }
+
+    /// Makes the content of `version` current again. This adds a new version, so the history is kept.
+    pub fn restore_version(&self, filename: &str, version: u64) -> Result<(), DatabaseError> {
+        self.commit_ops(vec![CatalogOp::Restore {
+            filename: Self::catalog_name(&self.catalog(), filename)?,
+            version,
+            timestamp: now(),
+            author: self.author(),
+        }])
+    }
+
+    /// Freezes the current content of every file under `name`.
+    ///
+    /// Nothing is copied: the snapshot only pins the cubes the files point at. The snapshot is
+    /// owned by the signed-in user.
+    pub fn create_snapshot(&self, name: &str) -> Result<(), DatabaseError> {
+        self.commit_ops(vec![CatalogOp::CreateSnapshot {
+            name: name.to_string(),
+            timestamp: now(),
+            owner: self.author(),
+        }])
+    }
+
+    /// Deletes a snapshot, releasing the beecells only it was keeping. Only its owner or an admin
+    /// may delete it.
+    pub fn delete_snapshot(&self, name: &str) -> Result<(), DatabaseError> {
+        self.commit_ops(vec![CatalogOp::DeleteSnapshot { name: name.to_string() }])
+    }
+
+    /// Every snapshot, sorted by name.
+    pub fn list_snapshots(&self) -> Vec<Snapshot> {
+        self.catalog().snapshots.values().cloned().collect()
+    }
+
+    fn find_snapshot<'a>(catalog: &'a Catalog, name: &str) -> Result<&'a Snapshot, DatabaseError> {
+        catalog.snapshots.get(name).ok_or(DatabaseError::SnapshotNotFound(name.to_string()))
+    }
+
+    /// Opens a snapshot for reading. Keys of encrypted files are unwrapped up front, so their
+    /// owners have to be known. Only files the signed-in user could read at the time are in it.
+    pub fn open_snapshot(&self, name: &str) -> Result<SnapshotReader, DatabaseError> {
+        let view = self.view();
+        let snapshot = Self::find_snapshot(&view.catalog, name)?;
+        let mut files = BTreeMap::new();
+        // Access is as it was when the snapshot was taken, unreadable files are left out
+        for (filename, file) in snapshot.files.iter().filter(|(_, file)| view.allows(file.acl.as_ref(), Permission::Read)) {
+            let cubes = self.cubes_of(&file.cube_ids)?;
+            let key = self.unwrap_key(file.key.as_ref())?;
+            files.insert(filename.clone(), SnapshotEntry { cubes, key });
+        }
+        Ok(SnapshotReader::new(snapshot, self.hive.store.clone(), files))
+    }
+
+    /// Lists the files added, removed and modified going from snapshot `from` to snapshot `to`.
+    pub fn diff_snapshots(&self, from: &str, to: &str) -> Result<SnapshotDiff, DatabaseError> {
+        let view = self.view();
+        let (from, to) = (Self::find_snapshot(&view.catalog, from)?, Self::find_snapshot(&view.catalog, to)?);
+        let mut diff = SnapshotDiff::between(from, to);
+        let readable = |snapshot: &Snapshot, filename: &String| view.allows(snapshot.files[filename].acl.as_ref(), Permission::Read);
+        diff.added.retain(|filename| readable(to, filename));
+        diff.removed.retain(|filename| readable(from, filename));
+        diff.modified.retain(|filename| readable(from, filename) && readable(to, filename));
+        Ok(diff)
+    }
+
+    // Every cube in the hive, by id
+    fn sorted_cubes(&self) -> Vec<Arc<Cube>> {
+        let mut cubes: Vec<Arc<Cube>> = read_lock(&self.hive.cubes).values().cloned().collect();
+        cubes.sort_by(|a, b| a.id.cmp(&b.id));
+        cubes
+    }
+
+    /// Reads back every beecell and parity shard of every cube a file, version or snapshot
+    /// reaches and reports the ones that are missing or corrupt.
+    ///
+    /// Beecells are checked as stored, so encrypted files are scrubbed without their keys.
+    pub fn scrub(&self) -> Result<ScrubReport, DatabaseError> {
+        let mut report = ScrubReport::default();
+
+        // Files deleted meanwhile keep their beecells until the scrub is done
+        let _staging = self.start_staging();
+        let catalog = self.catalog();
+        let reachable = catalog.reachable_cube_ids();
+        let mut cubes = self.sorted_cubes();
+        cubes.retain(|cube| reachable.contains(cube.id.as_str()));
+        for (filename, cube_ids) in &catalog.file_links {
+            for cube_id in cube_ids {
+                if !read_lock(&self.hive.cubes).contains_key(cube_id) {
+                    report.missing_cubes.push((filename.clone(), cube_id.clone()));
+                }
+            }
+        }
+
+        // Shared beecells are only read once, but every place they are used in is reported.
+        // The reads are spread over the rayon pool.
+        let mut sizes: HashMap<&str, u64> = HashMap::new();
+        for (_, address, size) in cubes.iter().flat_map(|cube| &cube.frames).flat_map(Frame::objects) {
+            sizes.insert(address, size);
+        }
+        let checked = sizes
+            .par_iter()
+            .map(|(address, _)| Ok((*address, load_object(&self.hive.store, address)?.err())))
+            .collect::<io::Result<HashMap<&str, Option<ScrubProblem>>>>()?;
+        report.bytes_checked = sizes.values().sum();
+
+        for cube in &cubes {
+            report.cubes_checked += 1;
+            for frame in &cube.frames {
+                for (object_id, address, _) in frame.objects() {
+                    report.beecells_checked += 1;
+                    if let Some(problem) = checked[address] {
+                        report.issues.push(ScrubIssue {
+                            cube_id: cube.id.clone(),
+                            frame_id: frame.id.clone(),
+                            beecell_id: object_id,
+                            hash: address.to_string(),
+                            problem,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Rebuilds missing or corrupt beecells and parity shards from the parity of their frames and
+    /// writes them back to the hive.
+    pub fn repair(&self) -> Result<RepairReport, DatabaseError> {
+        let mut report = RepairReport::default();
+
+        for cube in self.sorted_cubes() {
+            for frame in &cube.frames {
+                let issue = |object_id: &str, address: &str, problem| ScrubIssue {
+                    cube_id: cube.id.clone(),
+                    frame_id: frame.id.clone(),
+                    beecell_id: object_id.to_string(),
+                    hash: address.to_string(),
+                    problem,
+                };
+
+                let mut covered = vec![false; frame.beecells.len()];
+                for (stripe, parity) in frame.parity.iter().enumerate() {
+                    covered[parity.first..parity.first + parity.cells].fill(true);
+                    let objects = frame.stripe_objects(stripe);
+                    let mut shards = Vec::new();
+                    let mut damaged = Vec::new();
+                    for (i, (_, address, _)) in objects.iter().enumerate() {
+                        match load_object(&self.hive.store, address)? {
+                            Ok(data) => shards.push(Some(data)),
+                            Err(problem) => {
+                                shards.push(None);
+                                damaged.push((i, problem));
+                            }
+                        }
+                    }
+                    if damaged.is_empty() {
+                        continue;
+                    }
+
+                    let rebuilt = erasure::reconstruct(parity, &mut shards).is_ok();
+                    for (i, problem) in damaged {
+                        let (object_id, address, size) = &objects[i];
+                        let object = shards[i].as_ref().filter(|_| rebuilt).map(|shard| &shard[..*size as usize]);
+                        match object {
+                            Some(object) if calculate_hash(object) == *address => {
+                                self.hive.store.replace_beecell(address, object)?;
+                                report.repaired.push(issue(object_id, address, problem));
+                            }
+                            _ => report.unrecoverable.push(issue(object_id, address, problem)),
+                        }
+                    }
+                }
+
+                // Beecells without parity can only be reported
+                for (beecell, _) in frame.beecells.iter().zip(covered).filter(|(_, covered)| !covered) {
+                    if let Err(problem) = load_object(&self.hive.store, beecell.address())? {
+                        report.unrecoverable.push(issue(&beecell.id, beecell.address(), problem));
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    // Mark: every cube reachable from a file, version or snapshot, and everything in them.
+    // Sweep: whatever else is in the hive.
+    fn find_garbage(&self, catalog: &Catalog) -> Result<Garbage, DatabaseError> {
+        let cubes = read_lock(&self.hive.cubes);
+        let reachable_cubes = catalog.reachable_cube_ids();
+        let mut reachable_beecells = std::collections::HashSet::new();
+        for cube_id in &reachable_cubes {
+            reachable_beecells.extend(cubes.get(*cube_id).into_iter().flat_map(|cube| cube.addresses()));
+        }
+
+        let mut garbage = Garbage::default();
+        // Cube directories without a manifest were never completely written, they go as well
+        for cube_id in self.hive.store.cube_ids()? {
+            if !reachable_cubes.contains(cube_id.as_str()) {
+                let size = self.hive.store.cube_size(&cube_id)?;
+                garbage.cubes.push((cube_id, size));
+            }
+        }
+        for (address, size) in self.hive.store.beecells()? {
+            if !reachable_beecells.contains(address.as_str()) {
+                garbage.beecells.push((address, size));
+            }
+        }
+        Ok(garbage)
+    }
+
+    /// Reports what [`Database::collect_garbage`] would reclaim, without removing anything.
+    pub fn garbage_report(&self) -> Result<GcReport, DatabaseError> {
+        let garbage = self.find_garbage(&self.catalog())?;
+        Ok(GcReport {
+            dry_run: true,
+            cubes: garbage.cubes.len(),
+            beecells: garbage.beecells.len(),
+            reclaimable_bytes: garbage.cubes.iter().chain(&garbage.beecells).map(|(_, size)| size).sum(),
+        })
+    }
+
+    /// Removes every cube, frame and beecell that no file, version or snapshot can reach, and
+    /// recounts the references of what is left.
+    ///
+    /// Waits for the stores in flight to commit, their beecells aren't reachable yet. New stores
+    /// wait for the collection to finish.
+    pub fn collect_garbage(&self) -> Result<GcReport, DatabaseError> {
+        let staging = self.hive.staging_done.wait_while(lock(&self.hive.staging), |staging| *staging > 0).unwrap_or_else(PoisonError::into_inner);
+        let _commit = lock(&self.hive.commit_lock);
+        let mut catalog = (*self.catalog()).clone();
+        let garbage = self.find_garbage(&catalog)?;
+        catalog.refcounts = catalog.count_references(&read_lock(&self.hive.cubes));
+        self.hive.store.write_json(Path::new(CATALOG_FILE), &catalog)?;
+        *write_lock(&self.hive.catalog) = Arc::new(catalog);
+
+        for (cube_id, _) in &garbage.cubes {
+            self.hive.store.remove_cube(cube_id)?;
+            write_lock(&self.hive.cubes).remove(cube_id);
+        }
+        for (address, _) in &garbage.beecells {
+            self.hive.store.remove_beecell(address)?;
+        }
+        // What commits set aside is either garbage by now or referenced again
+        lock(&self.hive.released).clear();
+        drop(staging);
+
+        Ok(GcReport {
+            dry_run: false,
+            cubes: garbage.cubes.len(),
+            beecells: garbage.beecells.len(),
+            reclaimable_bytes: garbage.cubes.iter().chain(&garbage.beecells).map(|(_, size)| size).sum(),
+        })
+    }
+
+    /// Writes `filenames` to `writer` as a single `.hive` archive, for [`Database::import_archive`]
+    /// to read into another hive.
+    ///
+    /// Every version of every file goes in with its metadata, tags, cubes, frames, beecells and
+    /// parity, each stored object once. The archive describes its content up front and ends with
+    /// a checksum over everything. Encrypted files stay encrypted, so they can only be read where
+    /// their owner is registered with the same key. ACLs aren't exported.
+    pub fn export_archive<W: Write>(&self, filenames: &[&str], writer: W) -> Result<ArchiveReport, DatabaseError> {
+        let view = self.view();
+        let mut files: Vec<ArchivedFile> = Vec::new();
+        for filename in filenames {
+            let filename = normalize_filename(filename)?;
+            view.check_access(&filename, Permission::Read)?;
+            if files.iter().any(|file| file.filename == filename) {
+                continue;
+            }
+            let versions = view.catalog.versions.get(&filename).ok_or(DatabaseError::FileNotFound(filename.clone()))?;
+            files.push(ArchivedFile {
+                versions: versions.clone(),
+                metadata: view.catalog.metadata.get(&filename).cloned().unwrap_or_default(),
+                tags: view.catalog.tags.get(&filename).cloned().unwrap_or_default(),
+                filename,
+            });
+        }
+
+        // Versions and files sharing cubes or beecells share them in the archive too
+        let mut cube_ids = Vec::new();
+        let mut seen = HashSet::new();
+        for cube_id in files.iter().flat_map(|file| &file.versions).flat_map(|version| &version.cube_ids) {
+            if seen.insert(cube_id) {
+                cube_ids.push(cube_id.clone());
+            }
+        }
+        let cubes = self.cubes_of(&cube_ids)?;
+        let mut objects = Vec::new();
+        let mut seen = HashSet::new();
+        for address in cubes.iter().flat_map(|cube| cube.addresses()) {
+            if seen.insert(address) {
+                objects.push(address.to_string());
+            }
+        }
+
+        let manifest = ArchiveManifest {
+            created: now(),
+            files,
+            cubes: cube_ids,
+            objects,
+        };
+        let mut archive = ArchiveWriter::new(writer)?;
+        archive.write_manifest(&manifest)?;
+        for cube in &cubes {
+            archive.write_cube(&ArchivedCube {
+                id: cube.id.clone(),
+                root: cube.root.clone(),
+                frames: cube.frames.clone(),
+            })?;
+        }
+        for address in &manifest.objects {
+            let data = match load_object(&self.hive.store, address)? {
+                Ok(data) => data,
+                Err(ScrubProblem::Missing) => return Err(DatabaseError::IntegrityViolation(format!("{} is missing", address))),
+                Err(ScrubProblem::Corrupt) => return Err(DatabaseError::IntegrityViolation(format!("{} is corrupt", address))),
+            };
+            archive.write_object(address, &data)?;
+        }
+
+        Ok(ArchiveReport {
+            files: manifest.files.len(),
+            versions: manifest.files.iter().map(|file| file.versions.len()).sum(),
+            cubes: manifest.cubes.len(),
+            objects: manifest.objects.len(),
+            deduplicated: 0,
+            bytes: archive.finish()?,
+        })
+    }
+
+    /// Reads an archive written by [`Database::export_archive`] and adds its files, with their
+    /// whole history, owned by whoever is signed in.
+    ///
+    /// The versions are credited to whoever is signed in as well, and their content hashes are
+    /// taken from the content rather than from the archive. Versions encrypted for a user this
+    /// hive doesn't have can't be hashed, they are imported without a content hash.
+    ///
+    /// The checksum of the archive, the hash of every beecell and the roots of every cube are
+    /// checked, and beecells the hive already has aren't written again. The files are added all
+    /// together or not at all, so nothing is imported if any of them already exists. Beecells of
+    /// an archive found damaged part way are left for [`Database::collect_garbage`].
+    pub fn import_archive<R: Read>(&self, reader: R) -> Result<ArchiveReport, DatabaseError> {
+        let (mut archive, manifest) = ArchiveReader::open(reader)?;
+        let staging = self.start_staging();
+        let mut cubes = Vec::new();
+        let mut objects = HashSet::new();
+        let mut deduplicated = 0;
+        while let Some(record) = archive.next_record()? {
+            match record {
+                Record::Cube(cube) => cubes.push(Self::archived_cube(cube)?),
+                Record::Object { address, data } => {
+                    if calculate_hash(&data) != address {
+                        return Err(DatabaseError::InvalidArchive(format!("object {} is corrupt", address)));
+                    }
+                    if self.hive.store.has_beecell(&address) {
+                        deduplicated += 1;
+                    } else {
+                        self.hive.store.write_beecell(&address, &data)?;
+                    }
+                    objects.insert(address);
+                }
+            }
+        }
+
+        check_archive_is_complete(&manifest, &cubes, &objects)?;
+
+        let archived: HashMap<&str, &Cube> = cubes.iter().map(|cube| (cube.id.as_str(), cube)).collect();
+        let (author, imported_at) = (self.author(), now());
+        let mut ops = Vec::with_capacity(manifest.files.len());
+        for file in &manifest.files {
+            let ordered = file.versions.windows(2).all(|pair| pair[0].version < pair[1].version);
+            if file.versions.is_empty() || !ordered {
+                return Err(DatabaseError::InvalidArchive(format!("the history of {} is damaged", file.filename)));
+            }
+            file.tags.iter().try_for_each(|tag| check_tag(tag))?;
+            let versions = file
+                .versions
+                .iter()
+                .map(|version| self.imported_version(version, &archived, &author, imported_at))
+                .collect::<Result<Vec<_>, _>>()?;
+            ops.push(CatalogOp::Import {
+                filename: normalize_filename(&file.filename)?,
+                versions,
+                metadata: file.metadata.clone(),
+                tags: file.tags.clone(),
+                owner: self.author(),
+            });
+        }
+
+        for cube in cubes {
+            if !read_lock(&self.hive.cubes).contains_key(&cube.id) {
+                self.save_cube(&cube)?;
+                write_lock(&self.hive.cubes).insert(cube.id.clone(), Arc::new(cube));
+            }
+        }
+        self.commit_staged(ops, staging)?;
+
+        Ok(ArchiveReport {
+            files: manifest.files.len(),
+            versions: manifest.files.iter().map(|file| file.versions.len()).sum(),
+            cubes: manifest.cubes.len(),
+            objects: manifest.objects.len(),
+            deduplicated,
+            bytes: archive.position(),
+        })
+    }
+
+    // A version from an archive as this hive records it. Only what the cubes prove is kept: the
+    // content hash is worked out again, and the version is credited to whoever imports it.
+    fn imported_version(&self, version: &FileVersion, archived: &HashMap<&str, &Cube>, author: &str, imported_at: u64) -> Result<FileVersion, DatabaseError> {
+        let cubes = version
+            .cube_ids
+            .iter()
+            .map(|cube_id| archived.get(cube_id.as_str()).ok_or_else(|| DatabaseError::InvalidArchive(format!("cube {} is missing", cube_id))))
+            .collect::<Result<Vec<_>, _>>()?;
+        let content_hash = match self.unwrap_key(version.key.as_ref()) {
+            Ok(key) => content_digest(FileReader::new(self.hive.store.clone(), key, cubes.iter().flat_map(|cube| &cube.frames)))?,
+            Err(_) => String::new(),
+        };
+        Ok(FileVersion {
+            timestamp: version.timestamp.min(imported_at),
+            author: author.to_string(),
+            content_hash,
+            ..version.clone()
+        })
+    }
+
+    // Turns a cube read from an archive back into one the hive can hold, checking its roots
+    fn archived_cube(archived: ArchivedCube) -> Result<Cube, DatabaseError> {
+        let ArchivedCube { id, root, mut frames } = archived;
+        if !is_valid_id(&id) || !frames.iter().all(|frame| is_valid_id(&frame.id)) {
+            return Err(DatabaseError::InvalidArchive(format!("invalid cube id {:?}", id)));
+        }
+        // Cubes are named after their root, so one can't stand in for a cube the hive already has
+        if id != root {
+            return Err(DatabaseError::InvalidArchive(format!("cube {} isn't named after its root", id)));
+        }
+        frames.iter().try_for_each(check_archived_frame)?;
+        let roots_match = frames.iter().all(|frame| frame.root == Self::frame_root(&frame.beecells)) && root == Self::cube_root(&frames);
+        if !roots_match {
+            return Err(DatabaseError::InvalidArchive(format!("cube {} doesn't match its root", id)));
+        }
+        let size = Self::index_frames(&mut frames);
+        Ok(Cube { id, frames, root, size })
+    }
+
+    pub fn get_file_links(&self, filename: &str) -> Option<Vec<String>> {
+        let view = self.view();
+        let filename = Self::catalog_name(&view.catalog, filename).ok()?;
+        view.check_access(&filename, Permission::Read).ok()?;
+        view.catalog.file_links.get(&filename).cloned()
+    }
+
+    /// Returns bytes `[start, end)` of a file, reading only the beecells that hold them.
+    ///
+    /// `end` is clamped to the size of the file.
+    pub fn slice_file(&self, filename: &str, start: usize, end: usize) -> Result<Vec<u8>, DatabaseError> {
+        if start > end {
+            return Err(DatabaseError::Other(format!("Invalid range {}..{}", start, end)));
+        }
+        let (start, end) = (start as u64, end as u64);
+        let (cubes, key) = self.file_cubes(filename)?;
+        let key = self.unwrap_key(key.as_ref())?;
+
+        let mut sliced_file = Vec::new();
+        let mut cube_offset = 0;
+        for (cube_index, cube) in cubes.into_iter().enumerate() {
+            let cube_end = cube_offset + cube.size;
+            if cube_offset >= end {
+                break;
+            }
+            if cube_end <= start {
+                cube_offset = cube_end;
+                continue;
+            }
+
+            // The range relative to this cube
+            let range_start = start.saturating_sub(cube_offset);
+            let range_end = (end - cube_offset).min(cube.size);
+
+            let first_frame = cube.frames.partition_point(|frame| frame.offset + frame.size <= range_start);
+            for (frame_index, frame) in cube.frames.iter().enumerate().skip(first_frame) {
+                if frame.offset >= range_end {
+                    break;
+                }
+                let frame_start = range_start.saturating_sub(frame.offset);
+                let frame_end = (range_end - frame.offset).min(frame.size);
+
+                let first_cell = frame.beecells.partition_point(|beecell| beecell.offset + beecell.size <= frame_start);
+                for (cell_index, beecell) in frame.beecells.iter().enumerate().skip(first_cell) {
+                    if beecell.offset >= frame_end {
+                        break;
+                    }
+                    // Only the last cube and frame of a file can be short, so the index follows
+                    let index = (cube_index * FRAMES_PER_CUBE + frame_index) * CELLS_PER_FRAME + cell_index;
+                    let data = read_frame_beecell(&self.hive.store, frame, cell_index, key.as_ref(), index)?;
+                    let cell_start = frame_start.saturating_sub(beecell.offset) as usize;
+                    let cell_end = (frame_end - beecell.offset).min(beecell.size) as usize;
+                    sliced_file.extend_from_slice(&data[cell_start..cell_end]);
+                }
+            }
+
+            cube_offset = cube_end;
+        }
+
+        Ok(sliced_file)
+    }
+
+    pub fn previous_beecell_id(beecell_id: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+        beecell_id.hash(&mut hasher);
+        hasher.finish().to_string()
+    }
+
+    pub fn next_beecell_id(beecell_id: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+        beecell_id.hash(&mut hasher);
+        hasher.finish().to_string()
+    }
+
+    pub fn beecell_id(data: &[u8]) -> String {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        hasher.finish().to_string()
+    }
+
+    pub fn beecell_id_from_previous(previous_beecell_id: &str, data: &[u8]) -> String {
+        let mut hasher = DefaultHasher::new();
+        previous_beecell_id.hash(&mut hasher);
+        data.hash(&mut hasher);
+        hasher.finish().to_string()
+    }
+
+    pub fn beecell_id_from_next(next_beecell_id: &str, data: &[u8]) -> String {
+        let mut hasher = DefaultHasher::new();
+        next_beecell_id.hash(&mut hasher);
         data.hash(&mut hasher);
         hasher.finish().to_string()
     }
@@ -356,19 +2096,211 @@ impl Database {
         hasher.finish().to_string()
     }
 
-    pub fn delete_file(&mut self, filename: String) -> Result<(), DatabaseError> {
-        // Remove the file from the database
-        match self.file_links.remove(&filename) {
-            Some(_) => Ok(()),
-            None => Err(DatabaseError::FileNotFound(format!("File {} not found", filename))),
+    pub fn delete_file(&self, filename: String) -> Result<(), DatabaseError> {
+        let filename = Self::catalog_name(&self.catalog(), &filename)?;
+        self.commit_ops(vec![CatalogOp::Unlink { filename }])
+    }
+
+    /// Renames or moves a file, or a directory with everything in it. `to` must not exist yet,
+    /// missing directories above it are created.
+    pub fn rename_file(&self, from: String, to: String) -> Result<(), DatabaseError> {
+        let op = self.rename_op(from, &to)?;
+        self.commit_ops(vec![op])
+    }
+
+    /// Moves a file or directory into `directory`, keeping its name.
+    pub fn move_to(&self, from: &str, directory: &str) -> Result<(), DatabaseError> {
+        let directory = self.find_dir(directory)?;
+        let to = format!("{}/{}", directory, base_name(&normalize_path(from)?));
+        self.rename_file(from.to_string(), to)
+    }
+
+    fn rename_op(&self, from: String, to: &str) -> Result<CatalogOp, DatabaseError> {
+        Ok(CatalogOp::Rename { from: Self::catalog_name(&self.catalog(), &from)?, to: normalize_filename(to)? })
+    }
+
+    /// Creates a directory, and any missing directories above it. It is owned by the signed-in
+    /// user, and needs write access to the directory it is created in.
+    pub fn mkdir(&self, path: &str) -> Result<(), DatabaseError> {
+        let path = normalize_filename(path)?;
+        self.commit_ops(vec![CatalogOp::MakeDirectory { path, owner: self.author() }])
+    }
+
+    /// Removes an empty directory. Needs write access to it and to the directory it is in.
+    pub fn remove_dir(&self, path: &str) -> Result<(), DatabaseError> {
+        let path = normalize_path(path)?;
+        self.commit_ops(vec![CatalogOp::RemoveDirectory { path }])
+    }
+
+    // Normalizes the path of an existing directory, the root being the empty path
+    fn find_dir(&self, path: &str) -> Result<String, DatabaseError> {
+        let path = normalize_path(path)?;
+        if !path.is_empty() && !self.catalog().directories.contains(&path) {
+            return Err(DatabaseError::DirectoryNotFound(path));
+        }
+        Ok(path)
+    }
+
+    /// Lists what is directly in a directory, subdirectories first, each sorted by name.
+    pub fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, DatabaseError> {
+        let dir = self.find_dir(path)?;
+        let mut entries = self.entries_within(&dir);
+        entries.retain(|entry| parent_dir(&entry.path) == dir);
+        entries.sort_by(|a, b| (a.kind, &a.name).cmp(&(b.kind, &b.name)));
+        Ok(entries)
+    }
+
+    /// Lists everything below a directory, at any depth, sorted by path.
+    pub fn list_dir_recursive(&self, path: &str) -> Result<Vec<DirEntry>, DatabaseError> {
+        let dir = self.find_dir(path)?;
+        let mut entries = self.entries_within(&dir);
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    fn entries_within(&self, dir: &str) -> Vec<DirEntry> {
+        let view = self.view();
+        let directories = view.catalog.directories.iter().filter(|path| is_within(path, dir)).map(|path| DirEntry {
+            name: base_name(path).to_string(),
+            path: path.clone(),
+            kind: EntryKind::Directory,
+            size: 0,
+        });
+        // Files the signed-in user can't read aren't listed
+        let readable = |path: &String| view.allows(view.catalog.acls.get(path), Permission::Read);
+        let files = view.catalog.versions.iter().filter(|(path, _)| is_within(path, dir) && readable(path)).map(|(path, versions)| DirEntry {
+            name: base_name(path).to_string(),
+            path: path.clone(),
+            kind: EntryKind::File,
+            size: versions.last().map_or(0, |version| version.size),
+        });
+        directories.chain(files).collect()
+    }
+
+    /// Who owns a file or directory and who else may access it, `None` if it has no owner.
+    pub fn acl(&self, filename: &str) -> Result<Option<Acl>, DatabaseError> {
+        let view = self.view();
+        let filename = Self::catalog_name(&view.catalog, filename)?;
+        view.check_access(&filename, Permission::Read)?;
+        if !view.catalog.file_links.contains_key(&filename) && !view.catalog.directories.contains(&filename) {
+            return Err(DatabaseError::FileNotFound(filename));
         }
+        Ok(view.catalog.acls.get(&filename).cloned())
+    }
+
+    /// Gives `principal` `permission` on a file. Read and write access need the share
+    /// permission to be granted, anything more needs admin.
+    pub fn grant(&self, filename: &str, principal: Principal, permission: Permission) -> Result<(), DatabaseError> {
+        let filename = Self::catalog_name(&self.catalog(), filename)?;
+        self.commit_ops(vec![CatalogOp::GrantAcl { filename, principal, permission }])
+    }
+
+    /// Takes away whatever `principal` was granted on a file.
+    pub fn revoke(&self, filename: &str, principal: &Principal) -> Result<(), DatabaseError> {
+        let filename = Self::catalog_name(&self.catalog(), filename)?;
+        self.commit_ops(vec![CatalogOp::RevokeAcl { filename, principal: principal.clone() }])
+    }
+
+    /// Hands a file to another owner. Files without an owner can be claimed by anyone.
+    pub fn set_owner(&self, filename: &str, beeid: &str) -> Result<(), DatabaseError> {
+        let filename = Self::catalog_name(&self.catalog(), filename)?;
+        self.commit_ops(vec![CatalogOp::SetOwner { filename, owner: beeid.to_string() }])
+    }
+
+    /// Adds a user to a group, creating the group owned by the signed-in user if it is new.
+    pub fn add_to_group(&self, group: &str, beeid: &str) -> Result<(), DatabaseError> {
+        self.commit_ops(vec![CatalogOp::AddMember { group: group.to_string(), beeid: beeid.to_string(), owner: self.author() }])
+    }
+
+    /// Removes a user from a group. The group goes away with its last member.
+    pub fn remove_from_group(&self, group: &str, beeid: &str) -> Result<(), DatabaseError> {
+        self.commit_ops(vec![CatalogOp::RemoveMember { group: group.to_string(), beeid: beeid.to_string() }])
+    }
+
+    pub fn set_metadata(&self, filename: String, key: String, value: String) -> Result<(), DatabaseError> {
+        let filename = Self::catalog_name(&self.catalog(), &filename)?;
+        self.commit_ops(vec![CatalogOp::SetMetadata { filename, key, value: Some(value) }])
+    }
+
+    pub fn remove_metadata(&self, filename: String, key: String) -> Result<(), DatabaseError> {
+        let filename = Self::catalog_name(&self.catalog(), &filename)?;
+        self.commit_ops(vec![CatalogOp::SetMetadata { filename, key, value: None }])
+    }
+
+    pub fn add_tag(&self, filename: &str, tag: &str) -> Result<(), DatabaseError> {
+        self.set_tag(filename, tag, true)
+    }
+
+    pub fn remove_tag(&self, filename: &str, tag: &str) -> Result<(), DatabaseError> {
+        self.set_tag(filename, tag, false)
+    }
+
+    fn set_tag(&self, filename: &str, tag: &str, present: bool) -> Result<(), DatabaseError> {
+        check_tag(tag)?;
+        self.commit_ops(vec![CatalogOp::SetTag {
+            filename: Self::catalog_name(&self.catalog(), filename)?,
+            tag: tag.to_string(),
+            present,
+        }])
+    }
+
+    pub fn get_tags(&self, filename: &str) -> Result<BTreeSet<String>, DatabaseError> {
+        let view = self.view();
+        let filename = Self::catalog_name(&view.catalog, filename)?;
+        view.check_access(&filename, Permission::Read)?;
+        if !view.catalog.file_links.contains_key(&filename) {
+            return Err(DatabaseError::FileNotFound(filename));
+        }
+        Ok(view.catalog.tags.get(&filename).cloned().unwrap_or_default())
+    }
+
+    /// Finds the files matching a query such as `tag:release AND size>10MB AND owner:seigr_bee1`,
+    /// sorted by name. See [`Query::parse`] for the syntax.
+    ///
+    /// Tag and metadata equality terms are looked up in the index, everything else is checked
+    /// file by file.
+    pub fn query(&self, query: &str) -> Result<Vec<File>, DatabaseError> {
+        let query = Query::parse(query)?;
+        let view = self.view();
+        let filenames: Vec<String> = match query.candidates(&view.catalog.index) {
+            Some(candidates) => candidates.into_iter().collect(),
+            None => {
+                let mut filenames: Vec<String> = view.catalog.versions.keys().cloned().collect();
+                filenames.sort();
+                filenames
+            }
+        };
+
+        let mut found = Vec::new();
+        for filename in filenames {
+            if !view.allows(view.catalog.acls.get(&filename), Permission::Read) {
+                continue;
+            }
+            let Some(file) = view.catalog.versions.get(&filename).and_then(|versions| Self::file_info(&view.catalog, &filename, versions)) else {
+                continue;
+            };
+            if query.matches(&file)? {
+                found.push(file);
+            }
+        }
+        Ok(found)
+    }
+
+    pub fn get_metadata(&self, filename: &str) -> Result<BTreeMap<String, String>, DatabaseError> {
+        let view = self.view();
+        let filename = Self::catalog_name(&view.catalog, filename)?;
+        view.check_access(&filename, Permission::Read)?;
+        if !view.catalog.file_links.contains_key(&filename) {
+            return Err(DatabaseError::FileNotFound(filename));
+        }
+        Ok(view.catalog.metadata.get(&filename).cloned().unwrap_or_default())
     }
 
     fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
         hash(password, DEFAULT_COST)
     }
 
-    pub fn register_user(&mut self, username: String, email: String, password: String, beeid: String, key: [u8; 32]) -> Result<(), DatabaseError> {
+    pub fn register_user(&self, username: String, email: String, password: String, beeid: String, key: [u8; 32]) -> Result<(), DatabaseError> {
         // Hash the password
         let password_hash = Self::hash_password(&password)?;
         // Create the User struct
@@ -379,21 +2311,19 @@ impl Database {
             beeid: beeid.clone(), 
             authenticate: false, 
             key: key, 
-            password_hash: password_hash 
+            password_hash: password_hash,
+            quota: None,
+            admin: false,
         };
-        // Add the user to the users HashMap
-        self.users.insert(username, user);
-        Ok(())
+        self.add_user(user)
     }
 
     pub fn authenticate_user(&self, username: &str, password: &str) -> Result<(), DatabaseError> {
         // Look up the user in the users HashMap
-        match self.users.get(username) {
+        match read_lock(&self.hive.users).get(username) {
             Some(user) => {
-                // Hash the entered password
-                let entered_password_hash = Self::hash_password(password)?;
-                // Compare the hash of the entered password to the stored hash
-                if user.password_hash == entered_password_hash {
+                // bcrypt salts every hash, so the entered password is checked against the stored one
+                if verify(password, &user.password_hash)? {
                     Ok(())
                 } else {
                     Err(DatabaseError::AuthenticationFailed)
@@ -403,15 +2333,44 @@ impl Database {
         }
     }
 
-    pub fn add_user(&mut self, user: User) -> Result<(), DatabaseError> {
+    /// Authenticates `username` and hands out a handle on the same hive that acts for them, the
+    /// files stored through it are owned by them. This handle is left as it is.
+    pub fn sign_in(&self, username: &str, password: &str) -> Result<Database, DatabaseError> {
+        self.authenticate_user(username, password)?;
+        Ok(Database { hive: self.hive.clone(), username: Some(username.to_string()) })
+    }
+
+    /// Makes a user an admin or takes it away again. Only admins can, the first one is set in the
+    /// SeigrConfig or is the first user added.
+    pub fn set_admin(&self, username: &str, admin: bool) -> Result<(), DatabaseError> {
+        if !self.current_user().is_some_and(|user| user.admin) {
+            return Err(DatabaseError::PermissionDenied(username.to_string()));
+        }
+        self.update_user(username, |user| user.admin = admin)
+    }
+
+    /// A handle on the same hive that acts for nobody.
+    #[must_use]
+    pub fn sign_out(&self) -> Database {
+        Database { hive: self.hive.clone(), username: None }
+    }
+
+    pub fn current_user(&self) -> Option<User> {
+        read_lock(&self.hive.users).get(self.username.as_deref()?).cloned()
+    }
+
+    /// Adds a user. The first user of a hive that has none in its SeigrConfig becomes its admin.
+    pub fn add_user(&self, mut user: User) -> Result<(), DatabaseError> {
+        let mut users = write_lock(&self.hive.users);
+        user.admin |= users.is_empty();
         // Add the user to the users HashMap
-        self.users.insert(user.username.clone(), user);
+        users.insert(user.username.clone(), user);
         Ok(())
     }
 
     pub fn get_user(&self, username: String) -> Result<User, DatabaseError> {
         // Look up the user in the users HashMap
-        match self.users.get(&username) {
+        match read_lock(&self.hive.users).get(&username) {
             Some(user) => Ok(user.clone()),
             None => Err(DatabaseError::UserNotFound),
         }
@@ -419,9 +2378,1103 @@ impl Database {
 
     pub fn user_exists(&self, username: &str) -> Result<bool, DatabaseError> {
         // Look up the user in the users HashMap
-        match self.users.get(username) {
+        match read_lock(&self.hive.users).get(username) {
             Some(_) => Ok(true),
             None => Ok(false),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Codec;
+    use crate::listing::SortBy;
+    use crate::quota::UsageIndex;
+    use std::fs;
+    use std::io::{Seek, SeekFrom};
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+    use tempfile::TempDir;
+
+    fn sample_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        StdRng::seed_from_u64(seed).fill_bytes(&mut data);
+        data
+    }
+
+    fn open_database(dir: &TempDir) -> Database {
+        let key = [7u8; KEY_LENGTH];
+        let nonce = [0u8; NONCE_LENGTH];
+        Database::new(&dir.path().to_string_lossy(), &key, &nonce).expect("Failed to open database")
+    }
+
+    #[test]
+    fn test_store_file_persists_across_reopen() {
+        let dir = TempDir::new().unwrap();
+        let data = b"hello hive".to_vec();
+        {
+            let database = open_database(&dir);
+            database.store_file("hello.txt".to_string(), data.clone()).unwrap();
+        }
+
+        let database = open_database(&dir);
+        assert_eq!(database.retrieve_file("hello.txt").unwrap(), data);
+        assert!(dir.path().join("catalog.json").exists());
+    }
+
+    #[test]
+    fn test_delete_file_persists_across_reopen() {
+        let dir = TempDir::new().unwrap();
+        {
+            let database = open_database(&dir);
+            database.store_file("gone.txt".to_string(), b"bye".to_vec()).unwrap();
+            database.delete_file("gone.txt".to_string()).unwrap();
+        }
+
+        let database = open_database(&dir);
+        assert!(matches!(database.retrieve_file("gone.txt"), Err(DatabaseError::FileNotFound(_))));
+    }
+
+    #[test]
+    fn test_chunker_config_is_kept_with_the_hive() {
+        let dir = TempDir::new().unwrap();
+        let config = ChunkerConfig::new(64, 256, 1024).unwrap();
+        let data = sample_data(50_000, 1);
+        {
+            let database = open_database(&dir);
+            database.set_chunker_config(config).unwrap();
+            database.store_file("data.bin".to_string(), data.clone()).unwrap();
+            let cube_id = &database.get_file_links("data.bin").unwrap()[0];
+            assert!(read_lock(&database.hive.cubes)[cube_id].frames[0].beecells.len() > 1);
+        }
+
+        let database = open_database(&dir);
+        assert_eq!(database.chunker_config(), config);
+        assert_eq!(database.retrieve_file("data.bin").unwrap(), data);
+    }
+
+    #[test]
+    fn test_identical_content_is_stored_once() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        database.set_chunker_config(ChunkerConfig::new(64, 256, 1024).unwrap()).unwrap();
+        let data = sample_data(20_000, 2);
+
+        database.store_file("build-1.bin".to_string(), data.clone()).unwrap();
+        database.store_file("build-2.bin".to_string(), data.clone()).unwrap();
+
+        let stats = database.stats().unwrap();
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.logical_bytes, 2 * data.len() as u64);
+        assert_eq!(stats.physical_bytes, data.len() as u64);
+        assert_eq!(stats.beecells, 2 * stats.unique_beecells);
+
+        // The shared beecells stay until the last file referencing them is gone
+        let some_hash = database.catalog().refcounts.keys().next().unwrap().clone();
+        database.delete_file("build-1.bin".to_string()).unwrap();
+        assert_eq!(database.retrieve_file("build-2.bin").unwrap(), data);
+        database.delete_file("build-2.bin".to_string()).unwrap();
+        assert!(database.catalog().refcounts.is_empty());
+        assert!(!database.hive.store.has_beecell(&some_hash));
+    }
+
+    #[test]
+    fn test_files_get_their_own_cubes() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        let first = sample_data(1_000, 3);
+        let second = sample_data(1_000, 4);
+
+        database.store_file("first.bin".to_string(), first.clone()).unwrap();
+        database.store_file("second.bin".to_string(), second.clone()).unwrap();
+
+        assert_ne!(database.get_file_links("first.bin"), database.get_file_links("second.bin"));
+        assert_eq!(database.retrieve_file("first.bin").unwrap(), first);
+        assert_eq!(database.retrieve_file("second.bin").unwrap(), second);
+    }
+
+    #[test]
+    fn test_large_file_spans_several_cubes() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        database.set_chunker_config(ChunkerConfig::new(64, 64, 64).unwrap()).unwrap();
+        let data = sample_data(64 * (CELLS_PER_FRAME * FRAMES_PER_CUBE + 10), 5);
+
+        database.store_file("large.bin".to_string(), data.clone()).unwrap();
+
+        assert_eq!(database.get_file_links("large.bin").unwrap().len(), 2);
+        let database = open_database(&dir);
+        assert_eq!(database.retrieve_file("large.bin").unwrap(), data);
+    }
+
+    // Hands out data in small uneven pieces, like a socket would
+    struct TrickleReader {
+        data: Vec<u8>,
+        position: usize,
+    }
+
+    impl Read for TrickleReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = buf.len().min(7).min(self.data.len() - self.position);
+            buf[..count].copy_from_slice(&self.data[self.position..self.position + count]);
+            self.position += count;
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn test_store_reader_matches_store_file() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        database.set_chunker_config(ChunkerConfig::new(64, 256, 1024).unwrap()).unwrap();
+        let data = sample_data(30_000, 6);
+
+        database.store_file("whole.bin".to_string(), data.clone()).unwrap();
+        database.store_reader("streamed.bin".to_string(), TrickleReader { data: data.clone(), position: 0 }).unwrap();
+
+        assert_eq!(database.get_file_links("whole.bin"), database.get_file_links("streamed.bin"));
+        assert_eq!(database.retrieve_file("streamed.bin").unwrap(), data);
+    }
+
+    #[test]
+    fn test_open_reader_seeks() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        database.set_chunker_config(ChunkerConfig::new(64, 256, 1024).unwrap()).unwrap();
+        let data = sample_data(10_000, 7);
+        database.store_file("seek.bin".to_string(), data.clone()).unwrap();
+
+        let mut reader = database.open_reader("seek.bin").unwrap();
+        assert_eq!(reader.len(), data.len() as u64);
+        reader.seek(SeekFrom::Start(4_321)).unwrap();
+        let mut middle = vec![0u8; 2_000];
+        reader.read_exact(&mut middle).unwrap();
+        assert_eq!(middle, &data[4_321..6_321]);
+
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, &data[data.len() - 10..]);
+    }
+
+    #[tokio::test]
+    async fn test_async_store_and_retrieve() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        let data = sample_data(50_000, 8);
+
+        database.store_async_reader("async.bin".to_string(), data.as_slice()).await.unwrap();
+        let mut out = Vec::new();
+        let written = database.retrieve_to_async_writer("async.bin", &mut out).await.unwrap();
+
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(out, data);
+    }
+
+    fn beecell_path(dir: &TempDir, hash: &str) -> PathBuf {
+        dir.path().join("beecells").join(&hash[..2]).join(hash)
+    }
+
+    #[test]
+    fn test_corrupt_beecell_is_detected() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        database.set_chunker_config(ChunkerConfig::new(64, 256, 1024).unwrap()).unwrap();
+        database.store_file("archive.bin".to_string(), sample_data(5_000, 9)).unwrap();
+        assert!(database.scrub().unwrap().is_clean());
+
+        let cube_id = database.get_file_links("archive.bin").unwrap()[0].clone();
+        let bad = read_lock(&database.hive.cubes)[&cube_id].frames[0].beecells[1].clone();
+        let mut payload = fs::read(beecell_path(&dir, &bad.hash)).unwrap();
+        payload[0] ^= 0xFF;
+        fs::write(beecell_path(&dir, &bad.hash), payload).unwrap();
+
+        match database.retrieve_file("archive.bin") {
+            Err(DatabaseError::IntegrityViolation(message)) => assert!(message.contains(&bad.id)),
+            other => panic!("Expected an integrity violation, got {:?}", other),
+        }
+        let mut reader = database.open_reader("archive.bin").unwrap();
+        let error = DatabaseError::from(reader.read_to_end(&mut Vec::new()).unwrap_err());
+        assert!(matches!(error, DatabaseError::IntegrityViolation(_)));
+
+        let report = database.scrub().unwrap();
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!(report.issues[0].hash, bad.hash);
+        assert_eq!(report.issues[0].problem, ScrubProblem::Corrupt);
+    }
+
+    #[test]
+    fn test_scrub_reports_missing_beecells() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        database.store_file("lost.bin".to_string(), sample_data(2_000, 10)).unwrap();
+
+        let cube_id = database.get_file_links("lost.bin").unwrap()[0].clone();
+        let lost = read_lock(&database.hive.cubes)[&cube_id].frames[0].beecells[0].hash.clone();
+        fs::remove_file(beecell_path(&dir, &lost)).unwrap();
+
+        let report = database.scrub().unwrap();
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!(report.issues[0].problem, ScrubProblem::Missing);
+    }
+
+    #[test]
+    fn test_beecell_inclusion_proof() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        database.set_chunker_config(ChunkerConfig::new(64, 64, 64).unwrap()).unwrap();
+        let data = sample_data(64 * (CELLS_PER_FRAME * FRAMES_PER_CUBE + 150), 11);
+        database.store_file("proven.bin".to_string(), data.clone()).unwrap();
+        let file_root = database.file_root("proven.bin").unwrap();
+
+        // A beecell in the second frame of the second cube
+        let index = CELLS_PER_FRAME * FRAMES_PER_CUBE + 120;
+        let proof = database.prove_beecell("proven.bin", index).unwrap();
+        let chunk = &data[index * 64..(index + 1) * 64];
+        assert!(proof.verify(chunk, &file_root));
+        assert!(!proof.verify(&data[..64], &file_root));
+        assert!(!proof.verify(chunk, &read_lock(&database.hive.cubes)[&proof.cube_root].frames[0].root));
+    }
+
+    #[test]
+    fn test_slice_file_reads_byte_ranges() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        database.set_chunker_config(ChunkerConfig::new(64, 64, 64).unwrap()).unwrap();
+        let data = sample_data(64 * (CELLS_PER_FRAME * FRAMES_PER_CUBE + 150) + 17, 12);
+        database.store_file("movie.bin".to_string(), data.clone()).unwrap();
+
+        for (start, end) in [(0, 0), (0, 1), (63, 65), (6_399, 6_500), (102_000, 102_500), (data.len() - 5, data.len() + 100)] {
+            let expected = &data[start..end.min(data.len())];
+            assert_eq!(database.slice_file("movie.bin", start, end).unwrap(), expected, "range {}..{}", start, end);
+        }
+        assert!(database.slice_file("movie.bin", 10, 5).is_err());
+
+        // Beecells outside the range are never read, so a broken one there doesn't matter
+        let first = read_lock(&database.hive.cubes)[&database.get_file_links("movie.bin").unwrap()[0]].frames[0].beecells[0].hash.clone();
+        fs::remove_file(beecell_path(&dir, &first)).unwrap();
+        assert_eq!(database.slice_file("movie.bin", 1_000, 2_000).unwrap(), &data[1_000..2_000]);
+    }
+
+    #[test]
+    fn test_transaction_is_all_or_nothing() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        database.store_file("keep.txt".to_string(), b"keep".to_vec()).unwrap();
+
+        let mut transaction = Transaction::new();
+        transaction.store_file("new.txt".to_string(), b"new".to_vec());
+        transaction.delete_file("keep.txt".to_string());
+        transaction.delete_file("missing.txt".to_string());
+        assert!(matches!(transaction.commit(&database), Err(DatabaseError::FileNotFound(_))));
+
+        assert!(database.get_file_links("new.txt").is_none());
+        assert_eq!(database.retrieve_file("keep.txt").unwrap(), b"keep");
+
+        // What was stored for a transaction that fails part way is deleted again
+        let mut transaction = Transaction::new();
+        transaction.store_file("big.bin".to_string(), sample_data(50_000, 12));
+        transaction.rename_file("keep.txt".to_string(), "../outside.txt".to_string());
+        assert!(matches!(transaction.commit(&database), Err(DatabaseError::InvalidPath(_))));
+        assert_eq!(database.garbage_report().unwrap().beecells, 0);
+
+        let mut transaction = Transaction::new();
+        transaction.store_file("new.txt".to_string(), b"new".to_vec());
+        transaction.rename_file("keep.txt".to_string(), "kept.txt".to_string());
+        transaction.update_metadata("kept.txt".to_string(), "build".to_string(), Some("42".to_string()));
+        transaction.commit(&database).unwrap();
+
+        let database = open_database(&dir);
+        assert_eq!(database.retrieve_file("new.txt").unwrap(), b"new");
+        assert_eq!(database.retrieve_file("kept.txt").unwrap(), b"keep");
+        assert_eq!(database.get_metadata("kept.txt").unwrap()["build"], "42");
+        assert!(!dir.path().join(JOURNAL_FILE).exists());
+    }
+
+    #[test]
+    fn test_journal_is_replayed_on_open() {
+        let dir = TempDir::new().unwrap();
+        {
+            let database = open_database(&dir);
+            database.store_file("a.txt".to_string(), b"a".to_vec()).unwrap();
+            // Crash after the journal was written but before the catalog was
+            let entry = JournalEntry {
+                txid: database.catalog().last_txid + 1,
+                ops: vec![CatalogOp::Rename { from: "a.txt".to_string(), to: "b.txt".to_string() }],
+            };
+            database.hive.store.write_json(Path::new(JOURNAL_FILE), &entry).unwrap();
+        }
+
+        let database = open_database(&dir);
+        assert!(database.get_file_links("a.txt").is_none());
+        assert_eq!(database.retrieve_file("b.txt").unwrap(), b"a");
+        assert!(!dir.path().join(JOURNAL_FILE).exists());
+
+        // A journal that was already applied is just dropped
+        let entry = JournalEntry { txid: database.catalog().last_txid, ops: vec![CatalogOp::Unlink { filename: "b.txt".to_string() }] };
+        database.hive.store.write_json(Path::new(JOURNAL_FILE), &entry).unwrap();
+        let database = open_database(&dir);
+        assert_eq!(database.retrieve_file("b.txt").unwrap(), b"a");
+    }
+
+    #[test]
+    fn test_files_of_a_signed_in_user_are_encrypted() {
+        let dir = TempDir::new().unwrap();
+        let secret = b"customer record: account 1234".repeat(1000);
+        let register = |database: &Database| {
+            database
+                .register_user("alice".to_string(), "alice@example.com".to_string(), "hunter2".to_string(), "seigr_bee1".to_string(), [3u8; 32])
+                .unwrap();
+        };
+        {
+            let database = open_database(&dir);
+            database.set_chunker_config(ChunkerConfig::new(1024, 4096, 16384).unwrap()).unwrap();
+            register(&database);
+            assert!(matches!(database.sign_in("alice", "wrong"), Err(DatabaseError::AuthenticationFailed)));
+            let alice = database.sign_in("alice", "hunter2").unwrap();
+            alice.store_file("secret.txt".to_string(), secret.clone()).unwrap();
+            assert_eq!(alice.retrieve_file("secret.txt").unwrap(), secret);
+            assert!(matches!(database.retrieve_file("secret.txt"), Err(DatabaseError::PermissionDenied(_))));
+            assert!(alice.scrub().unwrap().is_clean());
+            alice.grant("secret.txt", Principal::User("seigr_bee9".to_string()), Permission::Read).unwrap();
+        }
+
+        // Nothing in the raw store gives the content away
+        for entry in walk(&dir.path().join("beecells")) {
+            let payload = fs::read(entry).unwrap();
+            assert!(!payload.windows(16).any(|window| window == &secret[..16]));
+        }
+
+        // Users are not kept by the hive, the owner's key has to be presented again
+        let database = open_database(&dir);
+        assert!(matches!(database.retrieve_file("secret.txt"), Err(DatabaseError::PermissionDenied(_))));
+        database
+            .register_user("dave".to_string(), "dave@example.com".to_string(), "secret".to_string(), "seigr_bee9".to_string(), [9u8; 32])
+            .unwrap();
+        let dave = database.sign_in("dave", "secret").unwrap();
+        assert!(matches!(dave.retrieve_file("secret.txt"), Err(DatabaseError::UserNotFound)));
+        register(&database);
+        assert_eq!(dave.slice_file("secret.txt", 5000, 9000).unwrap(), secret[5000..9000]);
+        let mut reader = dave.open_reader("secret.txt").unwrap();
+        let mut read_back = Vec::new();
+        reader.read_to_end(&mut read_back).unwrap();
+        assert_eq!(read_back, secret);
+    }
+
+    fn walk(dir: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(walk(&path));
+            } else {
+                files.push(path);
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn test_compressed_beecells_read_back() {
+        let dir = TempDir::new().unwrap();
+        let logs: Vec<u8> = (0..20_000).flat_map(|i| format!("{} GET /api/hive/{} 200\n", 1_700_000_000 + i, i % 97).into_bytes()).collect();
+        let noise = sample_data(50_000, 6);
+        {
+            let database = open_database(&dir);
+            database.set_chunker_config(ChunkerConfig::new(1024, 4096, 16384).unwrap()).unwrap();
+            database
+                .set_compression_policy(CompressionPolicy::new(Codec::Zstd).with_extension("lz4log", Codec::Lz4))
+                .unwrap();
+            database.store_file("access.log".to_string(), logs.clone()).unwrap();
+            database.store_file("access.lz4log".to_string(), logs.clone()).unwrap();
+            database.store_file("noise.bin".to_string(), noise.clone()).unwrap();
+        }
+
+        let database = open_database(&dir);
+        assert_eq!(database.compression_policy().codec, Codec::Zstd);
+        assert_eq!(database.retrieve_file("access.log").unwrap(), logs);
+        assert_eq!(database.retrieve_file("access.lz4log").unwrap(), logs);
+        assert_eq!(database.slice_file("access.log", 1000, 3000).unwrap(), logs[1000..3000]);
+        assert_eq!(database.retrieve_file("noise.bin").unwrap(), noise);
+        assert!(database.scrub().unwrap().is_clean());
+
+        let stats = database.stats().unwrap();
+        // Both copies of the logs together take less than half of one of them
+        assert!(stats.physical_bytes < (noise.len() + logs.len() / 2) as u64, "{:?}", stats);
+        // Random data doesn't shrink, so its beecells stay raw under their own hash
+        let noise_cube = &read_lock(&database.hive.cubes)[&database.get_file_links("noise.bin").unwrap()[0]];
+        assert!(noise_cube.frames[0].beecells.iter().all(|beecell| beecell.object.is_empty()));
+    }
+
+    #[test]
+    fn test_erasure_coding_rebuilds_lost_beecells() {
+        let dir = TempDir::new().unwrap();
+        let data = sample_data(100_000, 8);
+        let database = open_database(&dir);
+        database.set_chunker_config(ChunkerConfig::new(1024, 4096, 16384).unwrap()).unwrap();
+        database.set_erasure_config(Some(ErasureConfig::new(4, 2).unwrap())).unwrap();
+        database.store_file("archive.bin".to_string(), data.clone()).unwrap();
+
+        let database = open_database(&dir);
+        let frame = read_lock(&database.hive.cubes)[&database.get_file_links("archive.bin").unwrap()[0]].frames[0].clone();
+        assert_eq!(frame.parity[0].parity.len(), 2);
+
+        // Two losses per stripe are survivable, on read and by repair
+        fs::remove_file(beecell_path(&dir, frame.beecells[0].address())).unwrap();
+        fs::write(beecell_path(&dir, frame.beecells[2].address()), b"bit rot").unwrap();
+        fs::remove_file(beecell_path(&dir, &frame.parity[1].parity[0])).unwrap();
+        assert_eq!(database.retrieve_file("archive.bin").unwrap(), data);
+        assert_eq!(database.scrub().unwrap().issues.len(), 3);
+
+        let report = database.repair().unwrap();
+        assert_eq!(report.repaired.len(), 3);
+        assert!(report.unrecoverable.is_empty());
+        assert!(database.scrub().unwrap().is_clean());
+
+        // A third loss in the same stripe is not
+        for beecell in &frame.beecells[4..7] {
+            fs::remove_file(beecell_path(&dir, beecell.address())).unwrap();
+        }
+        assert!(matches!(database.retrieve_file("archive.bin"), Err(DatabaseError::IntegrityViolation(_))));
+        assert_eq!(database.repair().unwrap().unrecoverable.len(), 3);
+    }
+
+    #[test]
+    fn test_every_store_keeps_a_version() {
+        let dir = TempDir::new().unwrap();
+        let drafts: Vec<Vec<u8>> = (0..3).map(|seed| sample_data(5_000, 20 + seed)).collect();
+        {
+            let database = open_database(&dir);
+            database
+                .register_user("bob".to_string(), "bob@example.com".to_string(), "secret".to_string(), "seigr_bee2".to_string(), [4u8; 32])
+                .unwrap();
+            database.store_file("report.txt".to_string(), drafts[0].clone()).unwrap();
+            database.sign_in("bob", "secret").unwrap().store_file("report.txt".to_string(), drafts[1].clone()).unwrap();
+            database.store_file("report.txt".to_string(), drafts[2].clone()).unwrap();
+        }
+
+        let database = open_database(&dir);
+        let versions = database.list_versions("report.txt").unwrap();
+        assert_eq!(versions.iter().map(|version| version.version).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(versions[1].author, "seigr_bee2");
+        assert_eq!(versions[2].author, "");
+        assert!(versions.iter().all(|version| version.size == 5_000 && version.timestamp > 0));
+        assert_eq!(database.retrieve_version("report.txt", 1).unwrap(), drafts[0]);
+        assert_eq!(database.retrieve_file("report.txt").unwrap(), drafts[2]);
+        assert!(matches!(database.retrieve_version("report.txt", 9), Err(DatabaseError::VersionNotFound(_, 9))));
+
+        database.restore_version("report.txt", 1).unwrap();
+        assert_eq!(database.retrieve_file("report.txt").unwrap(), drafts[0]);
+        assert_eq!(database.list_versions("report.txt").unwrap().len(), 4);
+
+        // Deleting the file drops its whole history
+        database.delete_file("report.txt".to_string()).unwrap();
+        assert!(database.list_versions("report.txt").is_err());
+        assert!(database.catalog().refcounts.is_empty());
+    }
+
+    #[test]
+    fn test_stat_describes_stored_files() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        database
+            .register_user("carol".to_string(), "carol@example.com".to_string(), "secret".to_string(), "seigr_bee3".to_string(), [5u8; 32])
+            .unwrap();
+        let database = database.sign_in("carol", "secret").unwrap();
+        let (draft, release) = (sample_data(4_000, 50), sample_data(6_000, 51));
+        database.store_file("notes.txt".to_string(), draft).unwrap();
+        database.store_reader("notes.txt".to_string(), &release[..]).unwrap();
+        database.set_metadata("notes.txt".to_string(), "project".to_string(), "hive".to_string()).unwrap();
+        database.rename_file("notes.txt".to_string(), "notes.md".to_string()).unwrap();
+
+        let file = database.stat("notes.md").unwrap();
+        assert_eq!(file.filename, "notes.md");
+        assert_eq!(file.beeid, "seigr_bee3", "the owner is whoever stored the file first");
+        assert_eq!((file.size, file.version), (6_000, 2));
+        assert!(file.created > 0 && file.created <= file.modified);
+        assert_eq!(file.mime_type, "text/markdown");
+        assert_eq!(file.content_hash, calculate_hash(&release));
+        assert_eq!(file.attributes.get("project").map(String::as_str), Some("hive"));
+        assert!(file.data.is_empty());
+        assert!(matches!(database.stat("notes.txt"), Err(DatabaseError::FileNotFound(_))));
+    }
+
+    #[test]
+    fn test_snapshots_pin_file_contents() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        let (a1, a2, b) = (sample_data(3_000, 30), sample_data(3_000, 31), sample_data(3_000, 32));
+        database.store_file("a.bin".to_string(), a1.clone()).unwrap();
+        database.store_file("b.bin".to_string(), b.clone()).unwrap();
+        database.create_snapshot("monday").unwrap();
+        assert!(matches!(database.create_snapshot("monday"), Err(DatabaseError::SnapshotExists(_))));
+
+        database.store_file("a.bin".to_string(), a2.clone()).unwrap();
+        database.delete_file("b.bin".to_string()).unwrap();
+        database.store_file("c.bin".to_string(), b.clone()).unwrap();
+        database.create_snapshot("tuesday").unwrap();
+
+        // The snapshot still reads the old content, even after b.bin is gone
+        let monday = database.open_snapshot("monday").unwrap();
+        assert_eq!(monday.filenames().collect::<Vec<_>>(), vec!["a.bin", "b.bin"]);
+        assert_eq!(monday.retrieve_file("a.bin").unwrap(), a1);
+        let mut read_back = Vec::new();
+        monday.open_reader("b.bin").unwrap().read_to_end(&mut read_back).unwrap();
+        assert_eq!(read_back, b);
+
+        let diff = database.diff_snapshots("monday", "tuesday").unwrap();
+        assert_eq!(diff.added, vec!["c.bin"]);
+        assert_eq!(diff.removed, vec!["b.bin"]);
+        assert_eq!(diff.modified, vec!["a.bin"]);
+
+        // An encrypted file stored again with the same content gets new cubes, but isn't modified
+        register_team(&database);
+        let alice = database.sign_in("alice", "secret").unwrap();
+        alice.store_file("c.bin".to_string(), b.clone()).unwrap();
+        let cube_ids = alice.get_file_links("c.bin").unwrap();
+        alice.store_file("c.bin".to_string(), b.clone()).unwrap();
+        assert_ne!(alice.get_file_links("c.bin").unwrap(), cube_ids);
+        alice.create_snapshot("wednesday").unwrap();
+        assert_eq!(alice.diff_snapshots("tuesday", "wednesday").unwrap(), SnapshotDiff::default());
+        alice.delete_snapshot("wednesday").unwrap();
+
+        let database = open_database(&dir);
+        assert_eq!(database.list_snapshots().iter().map(|snapshot| snapshot.name.as_str()).collect::<Vec<_>>(), vec!["monday", "tuesday"]);
+        database.delete_snapshot("monday").unwrap();
+        database.delete_snapshot("tuesday").unwrap();
+        assert!(matches!(database.open_snapshot("monday"), Err(DatabaseError::SnapshotNotFound(_))));
+        database.delete_file("a.bin".to_string()).unwrap();
+        database.delete_file("c.bin".to_string()).unwrap();
+        assert!(database.catalog().refcounts.is_empty());
+    }
+
+    #[test]
+    fn test_garbage_collection_keeps_reachable_data() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        let (a1, a2, b) = (sample_data(3_000, 40), sample_data(3_000, 41), sample_data(3_000, 42));
+        database.store_file("a.bin".to_string(), a1.clone()).unwrap();
+        database.store_file("b.bin".to_string(), b.clone()).unwrap();
+        database.create_snapshot("pinned").unwrap();
+        database.store_file("a.bin".to_string(), a2.clone()).unwrap();
+        database.delete_file("b.bin".to_string()).unwrap();
+        database.store_file("c.bin".to_string(), sample_data(3_000, 43)).unwrap();
+        database.delete_file("c.bin".to_string()).unwrap();
+        assert_eq!(dir.path().join(CUBES_DIR).read_dir().unwrap().count(), 3, "c.bin goes with its cube");
+        // Left behind by interrupted writes
+        database.hive.store.write_beecell(&calculate_hash(b"orphan"), b"orphan").unwrap();
+        database.hive.store.write_json(&Path::new(CUBES_DIR).join("unfinished").join("frame.json"), &"partial").unwrap();
+        let pinned = database.garbage_report().unwrap();
+        assert_eq!((pinned.cubes, pinned.beecells), (1, 1), "b.bin is still pinned by the snapshot");
+
+        database.delete_snapshot("pinned").unwrap();
+        assert!(matches!(database.retrieve_file("b.bin"), Err(DatabaseError::FileNotFound(_))));
+        let before = walk(dir.path());
+        let report = database.garbage_report().unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report, pinned, "b.bin went with the snapshot");
+        assert_eq!(walk(dir.path()), before, "a dry run leaves the hive alone");
+
+        let collected = database.collect_garbage().unwrap();
+        assert_eq!((collected.cubes, collected.beecells, collected.reclaimable_bytes), (report.cubes, report.beecells, report.reclaimable_bytes));
+        assert_eq!(database.garbage_report().unwrap(), GcReport { dry_run: true, ..GcReport::default() });
+
+        let database = open_database(&dir);
+        assert_eq!(database.retrieve_file("a.bin").unwrap(), a2);
+        assert_eq!(database.retrieve_version("a.bin", 1).unwrap(), a1);
+        assert!(database.scrub().unwrap().is_clean());
+        database.delete_file("a.bin".to_string()).unwrap();
+        assert!(database.catalog().refcounts.is_empty());
+    }
+
+    #[test]
+    fn test_deleted_content_can_be_stored_again() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        database.set_erasure_config(Some(ErasureConfig::new(2, 1).unwrap())).unwrap();
+        database.set_chunker_config(ChunkerConfig::new(64, 256, 1024).unwrap()).unwrap();
+        let data = sample_data(5_000, 44);
+        database.store_file("first.bin".to_string(), data.clone()).unwrap();
+        let cube_id = database.get_file_links("first.bin").unwrap()[0].clone();
+        let checked = database.scrub().unwrap().beecells_checked;
+        database.delete_file("first.bin".to_string()).unwrap();
+        assert!(database.scrub().unwrap().is_clean());
+
+        database.store_file("second.bin".to_string(), data.clone()).unwrap();
+        assert_eq!(database.get_file_links("second.bin").unwrap(), vec![cube_id]);
+        let report = database.scrub().unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.beecells_checked, checked, "the parity is back too");
+        assert_eq!(database.retrieve_file("second.bin").unwrap(), data);
+    }
+
+    #[test]
+    fn test_directories_can_be_listed_and_moved() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        let spec = sample_data(2_000, 60);
+        database.store_file("/projects/hive/./docs/spec.md".to_string(), spec.clone()).unwrap();
+        database.store_file("projects/hive/build.log".to_string(), sample_data(500, 61)).unwrap();
+        database.mkdir("projects/hive/empty").unwrap();
+        database.mkdir("archive").unwrap();
+        assert!(matches!(database.mkdir("projects/hive/build.log/x"), Err(DatabaseError::FileExists(_))));
+        assert!(matches!(database.store_file("projects".to_string(), Vec::new()), Err(DatabaseError::FileExists(_))));
+        assert!(matches!(database.list_dir("../etc"), Err(DatabaseError::InvalidPath(_))));
+
+        // Files are found under any spelling of their path
+        assert_eq!(database.retrieve_file("/projects/hive/docs/spec.md").unwrap(), spec);
+        database.add_tag("./projects/hive/docs/spec.md", "draft").unwrap();
+        database.set_metadata("/projects//hive/docs/spec.md".to_string(), "lang".to_string(), "en".to_string()).unwrap();
+        let file = database.stat("/projects/hive/docs/spec.md").unwrap();
+        assert_eq!(file.filename, "projects/hive/docs/spec.md");
+        assert_eq!((file.tags.len(), file.attributes.len()), (1, 1));
+        assert_eq!(database.list_versions("/projects/hive/docs/spec.md").unwrap().len(), 1);
+
+        let listing = database.list_dir("projects/hive/").unwrap();
+        let names: Vec<_> = listing.iter().map(|entry| (entry.kind, entry.name.as_str())).collect();
+        assert_eq!(names, vec![(EntryKind::Directory, "docs"), (EntryKind::Directory, "empty"), (EntryKind::File, "build.log")]);
+        assert_eq!(listing[2].size, 500);
+        let root: Vec<_> = database.list_dir("/").unwrap().into_iter().map(|entry| entry.path).collect();
+        assert_eq!(root, vec!["archive", "projects"]);
+
+        // Moving a directory takes everything below it along, in one step
+        database.move_to("projects/hive", "archive").unwrap();
+        let database = open_database(&dir);
+        let paths: Vec<_> = database.list_dir_recursive("archive").unwrap().into_iter().map(|entry| entry.path).collect();
+        assert_eq!(paths, vec!["archive/hive", "archive/hive/build.log", "archive/hive/docs", "archive/hive/docs/spec.md", "archive/hive/empty"]);
+        assert_eq!(database.retrieve_file("archive/hive/docs/spec.md").unwrap(), spec);
+        assert!(database.list_dir("projects").unwrap().is_empty());
+        assert!(matches!(database.rename_file("archive".to_string(), "archive/inner".to_string()), Err(DatabaseError::InvalidPath(_))));
+
+        database.rename_file("archive/hive/docs/spec.md".to_string(), "spec.md".to_string()).unwrap();
+        assert!(matches!(database.remove_dir("archive/hive"), Err(DatabaseError::DirectoryNotEmpty(_))));
+        database.remove_dir("archive/hive/empty").unwrap();
+        assert!(matches!(database.list_dir("archive/hive/empty"), Err(DatabaseError::DirectoryNotFound(_))));
+    }
+
+    fn register_team(database: &Database) {
+        for (name, beeid) in [("alice", "seigr_bee1"), ("bob", "seigr_bee2"), ("carol", "seigr_bee3")] {
+            database
+                .register_user(name.to_string(), format!("{}@example.com", name), "secret".to_string(), beeid.to_string(), [1u8; 32])
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_acls_are_enforced_on_reads_and_writes() {
+        let dir = TempDir::new().unwrap();
+        let plan = sample_data(2_000, 70);
+        {
+            let database = open_database(&dir);
+            register_team(&database);
+            let alice = database.sign_in("alice", "secret").unwrap();
+            alice.store_file("team/plan.txt".to_string(), plan.clone()).unwrap();
+            alice.add_to_group("team", "seigr_bee2").unwrap();
+            alice.grant("team/plan.txt", Principal::Group("team".to_string()), Permission::Read).unwrap();
+        }
+
+        let database = open_database(&dir);
+        register_team(&database);
+        let denied = |result: Result<(), DatabaseError>| matches!(result, Err(DatabaseError::PermissionDenied(_)));
+        assert!(matches!(database.retrieve_file("team/plan.txt"), Err(DatabaseError::PermissionDenied(_))));
+
+        // Bob reads through the group, but can't change anything
+        let bob = database.sign_in("bob", "secret").unwrap();
+        assert_eq!(bob.retrieve_file("team/plan.txt").unwrap(), plan);
+        assert!(denied(bob.store_file("team/plan.txt".to_string(), Vec::new())));
+        assert!(denied(bob.delete_file("team/plan.txt".to_string())));
+        assert!(denied(bob.rename_file("team".to_string(), "ours".to_string())));
+        assert!(denied(bob.set_metadata("team/plan.txt".to_string(), "status".to_string(), "done".to_string())));
+        assert!(denied(bob.grant("team/plan.txt", Principal::User("seigr_bee3".to_string()), Permission::Read)));
+        assert!(denied(bob.add_to_group("team", "seigr_bee3")));
+
+        // Carol sees nothing until access is shared with her
+        let carol = database.sign_in("carol", "secret").unwrap();
+        assert!(carol.list_dir("team").unwrap().is_empty());
+        assert!(matches!(carol.stat("team/plan.txt"), Err(DatabaseError::PermissionDenied(_))));
+
+        // Everyone stays signed in as themselves on their own handle
+        let alice = database.sign_in("alice", "secret").unwrap();
+        alice.grant("team/plan.txt", Principal::User("seigr_bee2".to_string()), Permission::Share).unwrap();
+        bob.grant("team/plan.txt", Principal::User("seigr_bee3".to_string()), Permission::Write).unwrap();
+        assert!(denied(bob.grant("team/plan.txt", Principal::User("seigr_bee3".to_string()), Permission::Admin)));
+        assert!(denied(bob.set_owner("team/plan.txt", "seigr_bee2")));
+
+        carol.store_file("team/plan.txt".to_string(), b"revised".to_vec()).unwrap();
+        assert_eq!(carol.stat("team/plan.txt").unwrap().beeid, "seigr_bee1");
+
+        alice.revoke("team/plan.txt", &Principal::User("seigr_bee3".to_string())).unwrap();
+        assert!(matches!(carol.retrieve_file("team/plan.txt"), Err(DatabaseError::PermissionDenied(_))));
+    }
+
+    #[test]
+    fn test_snapshots_and_directories_need_permission() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        register_team(&database);
+        let denied = |result: Result<(), DatabaseError>| matches!(result, Err(DatabaseError::PermissionDenied(_)));
+        let alice = database.sign_in("alice", "secret").unwrap();
+        alice.create_snapshot("before-release").unwrap();
+        alice.mkdir("private/drafts").unwrap();
+        assert_eq!(alice.list_snapshots()[0].owner, "seigr_bee1");
+
+        // Bob can neither touch alice's snapshot nor her directories
+        let bob = database.sign_in("bob", "secret").unwrap();
+        assert!(denied(bob.delete_snapshot("before-release")));
+        assert!(denied(bob.mkdir("private/drafts/bob")));
+        assert!(denied(bob.remove_dir("private/drafts")));
+        assert!(denied(bob.rename_file("private".to_string(), "mine".to_string())));
+        assert!(denied(bob.store_file("private/drafts/notes.txt".to_string(), Vec::new())));
+        assert!(denied(bob.store_file("private/drafts/new/notes.txt".to_string(), Vec::new())));
+        bob.store_file("notes.txt".to_string(), Vec::new()).unwrap();
+        assert!(denied(bob.rename_file("notes.txt".to_string(), "private/drafts/notes.txt".to_string())));
+        bob.create_snapshot("bobs").unwrap();
+        bob.delete_snapshot("bobs").unwrap();
+        bob.mkdir("shared").unwrap();
+
+        alice.grant("private/drafts", Principal::User("seigr_bee2".to_string()), Permission::Write).unwrap();
+        bob.mkdir("private/drafts/bob").unwrap();
+        bob.remove_dir("private/drafts/bob").unwrap();
+
+        // Alice was registered first, so she is the admin who appoints others
+        assert!(denied(bob.set_admin("bob", true)));
+        alice.set_admin("carol", true).unwrap();
+        let carol = database.sign_in("carol", "secret").unwrap();
+        carol.delete_snapshot("before-release").unwrap();
+        assert!(carol.list_snapshots().is_empty());
+    }
+
+    #[test]
+    fn test_quotas_count_shared_beecells_fairly() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        register_team(&database);
+        let carol = database.sign_in("carol", "secret").unwrap();
+        database.sign_in("alice", "secret").unwrap().set_admin("carol", true).unwrap();
+        carol.set_quota("alice", Some(10_000)).unwrap();
+        let (report, draft) = (sample_data(6_000, 80), sample_data(6_000, 81));
+        // Stored by nobody in particular, so both copies share their beecells
+        database.store_file("report.bin".to_string(), report.clone()).unwrap();
+        database.store_file("copy.bin".to_string(), report).unwrap();
+
+        let alice = database.sign_in("alice", "secret").unwrap();
+        alice.set_owner("report.bin", "seigr_bee1").unwrap();
+        assert!(matches!(alice.store_file("draft.bin".to_string(), draft.clone()), Err(DatabaseError::QuotaExceeded(_, 10_000))));
+        assert!(alice.stat("draft.bin").is_err());
+
+        // Once bob owns the other copy, alice only pays for half of it
+        let bob = database.sign_in("bob", "secret").unwrap();
+        bob.set_owner("copy.bin", "seigr_bee2").unwrap();
+        let usage = database.usage();
+        assert_eq!(usage.iter().map(|usage| usage.stored_bytes).collect::<Vec<_>>(), vec![3_000, 3_000]);
+        let rebuilt = |database: &Database| UsageIndex::build(&database.catalog(), &read_lock(&database.hive.cubes)).reports().clone();
+        assert_eq!(database.catalog().usage.reports(), &rebuilt(&database));
+        assert_eq!(database.user_usage("seigr_bee2").quota, None);
+
+        alice.store_file("draft.bin".to_string(), draft).unwrap();
+        let usage = database.user_usage("seigr_bee1");
+        assert_eq!((usage.files, usage.versions, usage.logical_bytes, usage.quota), (2, 2, 12_000, Some(10_000)));
+        assert!(usage.stored_bytes > 9_000 && usage.stored_bytes <= 10_000);
+
+        // Going over quota some other way still leaves room to clean up
+        assert!(matches!(bob.set_quota("alice", Some(1_000)), Err(DatabaseError::PermissionDenied(_))));
+        carol.set_quota("alice", Some(1_000)).unwrap();
+        alice.delete_file("draft.bin".to_string()).unwrap();
+        assert_eq!(database.user_usage("seigr_bee1").stored_bytes, 3_000);
+        assert_eq!(database.catalog().usage.reports(), &rebuilt(&database));
+    }
+
+    #[test]
+    fn test_quotas_are_kept_in_the_hive_config() {
+        let dir = TempDir::new().unwrap();
+        {
+            let database = open_database(&dir);
+            register_team(&database);
+            let alice = database.sign_in("alice", "secret").unwrap();
+            alice.set_quota("bob", Some(5_000)).unwrap();
+            alice.set_admin("carol", true).unwrap();
+        }
+
+        let database = open_database(&dir);
+        assert_eq!(database.get_user("bob".to_string()).unwrap().quota, Some(5_000));
+        assert!(database.get_user("carol".to_string()).unwrap().admin);
+        let bob = database.sign_in("bob", "secret").unwrap();
+        assert!(matches!(bob.store_file("big.bin".to_string(), sample_data(6_000, 82)), Err(DatabaseError::QuotaExceeded(_, 5_000))));
+        assert_eq!(database.user_usage("seigr_bee2").quota, Some(5_000));
+    }
+
+    #[test]
+    fn test_list_files_filters_sorts_and_pages() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        register_team(&database);
+        for (filename, size) in [("logs/a.log", 300), ("logs/b.log", 100), ("logs/old/c.log", 200), ("notes.txt", 50)] {
+            database.store_file(filename.to_string(), sample_data(size, size as u64)).unwrap();
+        }
+        database.sign_in("alice", "secret").unwrap().store_file("logs/private.log".to_string(), sample_data(400, 1)).unwrap();
+
+        let names = |listing: &FileListing| listing.files.iter().map(|file| file.filename.clone()).collect::<Vec<_>>();
+        let all_logs = database.list_files(&ListOptions::new().with_prefix("logs/").sorted_by(SortBy::Size, false)).unwrap();
+        assert_eq!(names(&all_logs), vec!["logs/b.log", "logs/old/c.log", "logs/a.log"]);
+        assert_eq!(all_logs.files[2].size, 300);
+
+        let options = ListOptions::new().with_glob("logs/*.log").with_limit(1);
+        let first = database.list_files(&options).unwrap();
+        assert_eq!(names(&first), vec!["logs/a.log"]);
+        let second = database.list_files(&options.clone().with_continuation(first.continuation.as_ref().unwrap())).unwrap();
+        assert_eq!(names(&second), vec!["logs/b.log"]);
+        assert!(second.continuation.is_none());
+
+        let matching = database.list_files(&ListOptions::new().with_regex(r"^[a-z]+\.txt$")).unwrap();
+        assert_eq!(names(&matching), vec!["notes.txt"]);
+    }
+
+    #[test]
+    fn test_query_finds_files_by_tags_and_metadata() {
+        let dir = TempDir::new().unwrap();
+        {
+            let database = open_database(&dir);
+            for (build, channel, size) in [(41, "beta", 2_000), (42, "stable", 12_000), (43, "stable", 3_000)] {
+                let filename = format!("builds/app-{}.tar", build);
+                database.store_file(filename.clone(), sample_data(size, build)).unwrap();
+                database.set_metadata(filename.clone(), "build".to_string(), build.to_string()).unwrap();
+                database.set_metadata(filename.clone(), "channel".to_string(), channel.to_string()).unwrap();
+                database.add_tag(&filename, "release").unwrap();
+            }
+            database.remove_tag("builds/app-43.tar", "release").unwrap();
+            database.rename_file("builds/app-42.tar".to_string(), "archive/app-42.tar".to_string()).unwrap();
+            assert!(database.add_tag("archive/app-42.tar", "two words").is_err());
+        }
+
+        let database = open_database(&dir);
+        let names = |query: &str| database.query(query).unwrap().into_iter().map(|file| file.filename).collect::<Vec<_>>();
+        assert_eq!(names("tag:release AND channel:stable"), vec!["archive/app-42.tar"]);
+        assert_eq!(names("tag:release AND size>10KB"), vec!["archive/app-42.tar"]);
+        assert_eq!(names("build>=42 OR channel=beta"), vec!["archive/app-42.tar", "builds/app-41.tar", "builds/app-43.tar"]);
+        assert_eq!(names("NOT tag:release"), vec!["builds/app-43.tar"]);
+        assert_eq!(names("name:builds/* build<43"), vec!["builds/app-41.tar"]);
+        assert_eq!(database.get_tags("archive/app-42.tar").unwrap().into_iter().collect::<Vec<_>>(), vec!["release"]);
+        assert!(matches!(database.query("tag:release AND"), Err(DatabaseError::InvalidQuery(_))));
+
+        // The index follows renames and removed tags
+        assert_eq!(database.catalog().index, {
+            let mut catalog = (*database.catalog()).clone();
+            catalog.index = Default::default();
+            catalog.migrate_index();
+            catalog.index
+        });
+    }
+
+    #[test]
+    fn test_find_by_hash_returns_every_version_with_the_content() {
+        let dir = TempDir::new().unwrap();
+        let (artifact, other) = (sample_data(5_000, 90), sample_data(5_000, 91));
+        let digest = content_digest(&artifact[..]).unwrap();
+        {
+            let database = open_database(&dir);
+            assert!(database.find_by_hash(&digest).is_empty());
+            database.store_file("a.bin".to_string(), artifact.clone()).unwrap();
+            database.store_file("b.bin".to_string(), other).unwrap();
+            database.store_file("b.bin".to_string(), artifact.clone()).unwrap();
+            database.store_file("b.bin".to_string(), Vec::new()).unwrap();
+            database.rename_file("a.bin".to_string(), "c.bin".to_string()).unwrap();
+        }
+
+        let database = open_database(&dir);
+        assert_eq!(database.stat("c.bin").unwrap().content_hash, digest);
+        assert_eq!(database.find_by_hash(&digest.to_uppercase()), vec![("b.bin".to_string(), 2), ("c.bin".to_string(), 1)]);
+        database.delete_file("c.bin".to_string()).unwrap();
+        database.restore_version("b.bin", 2).unwrap();
+        assert_eq!(database.find_by_hash(&digest), vec![("b.bin".to_string(), 2), ("b.bin".to_string(), 4)]);
+    }
+
+    #[test]
+    fn test_archives_carry_files_between_hives() {
+        let (source_dir, target_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (first, second, shared) = (sample_data(70_000, 100), sample_data(70_000, 101), sample_data(30_000, 102));
+        let mut archive = Vec::new();
+        {
+            let source = open_database(&source_dir);
+            source.store_file("data/set.bin".to_string(), first).unwrap();
+            source.store_file("data/set.bin".to_string(), second.clone()).unwrap();
+            source.set_metadata("data/set.bin".to_string(), "origin".to_string(), "lab".to_string()).unwrap();
+            source.add_tag("data/set.bin", "release").unwrap();
+            source.store_file("shared.bin".to_string(), shared.clone()).unwrap();
+            let report = source.export_archive(&["data/set.bin", "shared.bin"], &mut archive).unwrap();
+            assert_eq!((report.files, report.versions, report.bytes), (2, 3, archive.len() as u64));
+        }
+
+        let target = open_database(&target_dir);
+        target.store_file("local.bin".to_string(), shared.clone()).unwrap();
+        let report = target.import_archive(&archive[..]).unwrap();
+        assert!(report.deduplicated > 0);
+        assert_eq!(target.retrieve_file("data/set.bin").unwrap(), second);
+        assert_eq!(target.retrieve_file("shared.bin").unwrap(), shared);
+        assert_eq!(target.list_versions("data/set.bin").unwrap().len(), 2);
+        assert_eq!(target.get_metadata("data/set.bin").unwrap().get("origin").map(String::as_str), Some("lab"));
+        assert_eq!(target.query("tag:release").unwrap().len(), 1);
+        assert!(target.scrub().unwrap().is_clean());
+
+        // Nothing is imported twice, and a damaged archive isn't imported at all
+        assert!(matches!(target.import_archive(&archive[..]), Err(DatabaseError::FileExists(_))));
+        let mut damaged = archive.clone();
+        let middle = damaged.len() / 2;
+        damaged[middle] ^= 1;
+        let other_dir = TempDir::new().unwrap();
+        let other = open_database(&other_dir);
+        assert!(matches!(other.import_archive(&damaged[..]), Err(DatabaseError::InvalidArchive(_))));
+        assert!(matches!(other.retrieve_file("shared.bin"), Err(DatabaseError::FileNotFound(_))));
+    }
+
+    type Tamper = Box<dyn Fn(&mut ArchiveManifest, &mut ArchivedCube)>;
+
+    // Rewrites an archive with its manifest and cubes changed by `tamper`, and a checksum that
+    // matches again
+    fn tamper_with_archive(archive: &[u8], tamper: impl Fn(&mut ArchiveManifest, &mut ArchivedCube)) -> Vec<u8> {
+        let (mut reader, mut manifest) = ArchiveReader::open(archive).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            records.push(record);
+        }
+        let mut cubes = Vec::new();
+        for record in &mut records {
+            if let Record::Cube(cube) = record {
+                tamper(&mut manifest, cube);
+                cubes.push(cube.id.clone());
+            }
+        }
+        manifest.cubes = cubes;
+
+        let mut tampered = Vec::new();
+        let mut writer = ArchiveWriter::new(&mut tampered).unwrap();
+        writer.write_manifest(&manifest).unwrap();
+        for record in &records {
+            match record {
+                Record::Cube(cube) => writer.write_cube(cube).unwrap(),
+                Record::Object { address, data } => writer.write_object(address, data).unwrap(),
+            }
+        }
+        writer.finish().unwrap();
+        tampered
+    }
+
+    #[test]
+    fn test_tampered_archives_are_rejected() {
+        let (source_dir, target_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let content = sample_data(40_000, 105);
+        let mut archive = Vec::new();
+        {
+            let source = open_database(&source_dir);
+            source.set_erasure_config(Some(ErasureConfig::new(4, 2).unwrap())).unwrap();
+            source.store_file("report.pdf".to_string(), content.clone()).unwrap();
+            source.export_archive(&["report.pdf"], &mut archive).unwrap();
+        }
+        let target = open_database(&target_dir);
+        target.store_file("local.bin".to_string(), sample_data(10_000, 106)).unwrap();
+        let local_cube = target.catalog().versions["local.bin"][0].cube_ids[0].clone();
+
+        let tampers: Vec<Tamper> = vec![
+            // Poses as a cube the target already has, which it would keep instead
+            Box::new(move |manifest, cube| {
+                manifest.files[0].versions[0].cube_ids = vec![local_cube.clone()];
+                cube.id = local_cube.clone();
+            }),
+            Box::new(|_, cube| cube.frames[0].parity[0].first = 5),
+            Box::new(|_, cube| {
+                let stripe = &mut cube.frames[0].parity[0];
+                stripe.parity = vec![stripe.parity[0].clone(); 300];
+            }),
+            Box::new(|_, cube| cube.frames[0].parity[0].shard_size = 1),
+            Box::new(|_, cube| cube.frames[0].beecells[0].size = u64::MAX / 2),
+        ];
+        for tamper in &tampers {
+            let tampered = tamper_with_archive(&archive, tamper);
+            assert!(matches!(target.import_archive(&tampered[..]), Err(DatabaseError::InvalidArchive(_))));
+            assert!(matches!(target.stat("report.pdf"), Err(DatabaseError::FileNotFound(_))));
+        }
+
+        // Claims about the versions themselves aren't taken on trust
+        let local_hash = content_digest(&sample_data(10_000, 106)[..]).unwrap();
+        let claims = tamper_with_archive(&archive, |manifest, _| {
+            for version in &mut manifest.files[0].versions {
+                version.content_hash = local_hash.clone();
+                version.author = "seigr_bee1".to_string();
+                version.timestamp = u64::MAX;
+            }
+        });
+        target.import_archive(&claims[..]).unwrap();
+        assert_eq!(target.find_by_hash(&local_hash), vec![("local.bin".to_string(), 1)]);
+        assert_eq!(target.find_by_hash(&content_digest(&content[..]).unwrap()), vec![("report.pdf".to_string(), 1)]);
+        let version = &target.list_versions("report.pdf").unwrap()[0];
+        assert!(version.author.is_empty() && version.timestamp < u64::MAX);
+        assert_eq!(target.retrieve_file("report.pdf").unwrap(), content);
+        assert_eq!(target.retrieve_file("local.bin").unwrap(), sample_data(10_000, 106));
+        assert!(target.scrub().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_threads_share_a_database() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        database.store_file("shared.bin".to_string(), sample_data(50_000, 110)).unwrap();
+
+        std::thread::scope(|scope| {
+            for writer in 0..4u64 {
+                let database = &database;
+                scope.spawn(move || {
+                    for round in 0..5 {
+                        database.store_file(format!("writer{}/file.bin", writer), sample_data(40_000, writer * 10 + round)).unwrap();
+                        // Every writer stores and deletes the same content, nobody may lose it to another
+                        database.store_file(format!("writer{}/common.bin", writer), sample_data(20_000, 111)).unwrap();
+                        assert_eq!(database.retrieve_file(&format!("writer{}/common.bin", writer)).unwrap(), sample_data(20_000, 111));
+                        database.delete_file(format!("writer{}/common.bin", writer)).unwrap();
+                    }
+                });
+            }
+            for _ in 0..2 {
+                let database = &database;
+                scope.spawn(move || {
+                    for _ in 0..5 {
+                        assert_eq!(database.retrieve_file("shared.bin").unwrap(), sample_data(50_000, 110));
+                        assert!(database.scrub().unwrap().is_clean());
+                    }
+                });
+            }
+            scope.spawn(|| database.collect_garbage().unwrap());
+        });
+
+        for writer in 0..4u64 {
+            let filename = format!("writer{}/file.bin", writer);
+            assert_eq!(database.retrieve_file(&filename).unwrap(), sample_data(40_000, writer * 10 + 4));
+            assert_eq!(database.list_versions(&filename).unwrap().len(), 5);
+        }
+        database.collect_garbage().unwrap();
+        assert!(database.scrub().unwrap().is_clean());
+        assert_eq!(database.garbage_report().unwrap().beecells, 0);
+    }
+
+    #[test]
+    fn test_concurrent_sharing_keeps_every_grant() {
+        let dir = TempDir::new().unwrap();
+        let database = open_database(&dir);
+        register_team(&database);
+        let alice = database.sign_in("alice", "secret").unwrap();
+        alice.store_file("plan.txt".to_string(), sample_data(1_000, 120)).unwrap();
+
+        std::thread::scope(|scope| {
+            for member in 0..8 {
+                let alice = &alice;
+                scope.spawn(move || {
+                    let beeid = format!("seigr_bee{}", 10 + member);
+                    alice.grant("plan.txt", Principal::User(beeid.clone()), Permission::Read).unwrap();
+                    alice.add_to_group("team", &beeid).unwrap();
+                });
+            }
+        });
+
+        assert_eq!(alice.acl("plan.txt").unwrap().unwrap().entries.len(), 8);
+        assert_eq!(alice.catalog().groups["team"].members.len(), 8);
+        alice.remove_from_group("team", "seigr_bee10").unwrap();
+        assert_eq!(alice.catalog().groups["team"].members.len(), 7);
+    }
+}
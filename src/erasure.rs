@@ -0,0 +1,111 @@
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+
+use crate::database::DatabaseError;
+
+/// Most shards, data and parity together, a stripe can have.
+pub const MAX_SHARDS: usize = 256;
+
+/// Reed-Solomon layout used for new frames: every `data_shards` beecells of a frame get
+/// `parity_shards` parity beecells, and any `parity_shards` of them can be lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErasureConfig {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+}
+
+impl ErasureConfig {
+    pub fn new(data_shards: usize, parity_shards: usize) -> Result<Self, String> {
+        let config = ErasureConfig { data_shards, parity_shards };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.data_shards == 0 || self.parity_shards == 0 {
+            return Err("Erasure coding needs at least one data and one parity shard".to_string());
+        }
+        if self.data_shards + self.parity_shards > MAX_SHARDS {
+            return Err(format!("At most {} shards are supported, got {} + {}", MAX_SHARDS, self.data_shards, self.parity_shards));
+        }
+        Ok(())
+    }
+}
+
+/// Parity over the beecells `first..first + cells` of a frame.
+///
+/// Shards are the beecells as stored, zero padded to `shard_size`. The parity shards are kept in
+/// the hive like beecells, under their hashes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ParityStripe {
+    pub(crate) first: usize,
+    pub(crate) cells: usize,
+    pub(crate) shard_size: u64,
+    pub(crate) parity: Vec<String>,
+}
+
+fn codec(data_shards: usize, parity_shards: usize) -> Result<ReedSolomon, DatabaseError> {
+    ReedSolomon::new(data_shards, parity_shards).map_err(|error| DatabaseError::Other(format!("Invalid erasure layout: {}", error)))
+}
+
+/// Computes `parity_shards` parity shards over `data`, returning them with the shard size.
+pub(crate) fn compute_parity(data: &[Vec<u8>], parity_shards: usize) -> Result<(u64, Vec<Vec<u8>>), DatabaseError> {
+    let shard_size = data.iter().map(Vec::len).max().unwrap_or(0).max(1);
+    let mut shards: Vec<Vec<u8>> = data.iter().map(|shard| padded(shard, shard_size)).collect();
+    shards.extend((0..parity_shards).map(|_| vec![0u8; shard_size]));
+
+    codec(data.len(), parity_shards)?
+        .encode(&mut shards)
+        .map_err(|error| DatabaseError::Other(format!("Failed to compute parity: {}", error)))?;
+    Ok((shard_size as u64, shards.split_off(data.len())))
+}
+
+/// Rebuilds the missing shards of a stripe. `shards` holds the data shards, unpadded, followed by
+/// the parity shards, with `None` for every shard that was lost.
+pub(crate) fn reconstruct(stripe: &ParityStripe, shards: &mut [Option<Vec<u8>>]) -> Result<(), DatabaseError> {
+    let shard_size = stripe.shard_size as usize;
+    for shard in shards.iter_mut().flatten() {
+        shard.resize(shard_size, 0);
+    }
+    codec(stripe.cells, stripe.parity.len())?
+        .reconstruct(shards)
+        .map_err(|error| DatabaseError::IntegrityViolation(format!("Cannot reconstruct beecells: {}", error)))
+}
+
+fn padded(shard: &[u8], size: usize) -> Vec<u8> {
+    let mut padded = shard.to_vec();
+    padded.resize(size, 0);
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lost_shards_are_rebuilt_from_parity() {
+        let data: Vec<Vec<u8>> = vec![b"first beecell".to_vec(), b"second".to_vec(), b"third, the longest one".to_vec()];
+        let (shard_size, parity) = compute_parity(&data, 2).unwrap();
+        let stripe = ParityStripe { first: 0, cells: 3, shard_size, parity: vec![String::new(); 2] };
+
+        let mut shards: Vec<Option<Vec<u8>>> = data.iter().cloned().map(Some).chain(parity.into_iter().map(Some)).collect();
+        shards[0] = None;
+        shards[2] = None;
+        reconstruct(&stripe, &mut shards).unwrap();
+        assert_eq!(&shards[0].as_ref().unwrap()[..data[0].len()], &data[0][..]);
+        assert_eq!(&shards[2].as_ref().unwrap()[..data[2].len()], &data[2][..]);
+
+        shards[0] = None;
+        shards[1] = None;
+        shards[3] = None;
+        assert!(reconstruct(&stripe, &mut shards).is_err());
+    }
+
+    #[test]
+    fn test_invalid_config_is_rejected() {
+        assert!(ErasureConfig::new(0, 2).is_err());
+        assert!(ErasureConfig::new(4, 0).is_err());
+        assert!(ErasureConfig::new(200, 100).is_err());
+        assert!(ErasureConfig::new(10, 4).is_ok());
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub const BEECELLS_DIR: &str = "beecells";
+pub const CUBES_DIR: &str = "cubes";
+pub const CATALOG_FILE: &str = "catalog.json";
+pub const JOURNAL_FILE: &str = "journal.json";
+
+/// On-disk layout of a hive.
+///
+/// ```text
+/// <root>/
+///   catalog.json                     file links and other database state
+///   journal.json                     transaction being committed, if any
+///   beecells/<ab>/<hash>             beecell payloads, addressed by their Blake2b hash
+///   cubes/<cube_id>/cube.json        cube manifest listing its frames
+///   cubes/<cube_id>/<frame_id>.json  frame manifest listing its beecells
+/// ```
+///
+/// Beecells are immutable once written, so writing a hash that already exists is a no-op.
+#[derive(Debug, Clone)]
+pub struct HiveStore {
+    root: PathBuf,
+}
+
+impl HiveStore {
+    /// Opens the hive at `root`, creating the directory layout if it doesn't exist yet.
+    pub fn open<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(root.join(BEECELLS_DIR))?;
+        fs::create_dir_all(root.join(CUBES_DIR))?;
+        Ok(HiveStore { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn beecell_path(&self, hash: &str) -> io::Result<PathBuf> {
+        // Hashes end up in paths, so only accept what calculate_hash produces
+        if hash.len() < 2 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid beecell hash: {}", hash)));
+        }
+        Ok(self.root.join(BEECELLS_DIR).join(&hash[..2]).join(hash))
+    }
+
+    pub fn has_beecell(&self, hash: &str) -> bool {
+        self.beecell_path(hash).map(|path| path.exists()).unwrap_or(false)
+    }
+
+    pub fn write_beecell(&self, hash: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.beecell_path(hash)?;
+        if path.exists() {
+            // Same hash, same content: the beecell is already stored
+            return Ok(());
+        }
+        write_atomic(&path, data)
+    }
+
+    /// Writes a beecell even if something is already stored under `hash`, to fix a damaged copy.
+    pub fn replace_beecell(&self, hash: &str, data: &[u8]) -> io::Result<()> {
+        write_atomic(&self.beecell_path(hash)?, data)
+    }
+
+    pub fn read_beecell(&self, hash: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.beecell_path(hash)?)
+    }
+
+    pub fn remove_beecell(&self, hash: &str) -> io::Result<()> {
+        match fs::remove_file(self.beecell_path(hash)?) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Lists every beecell stored in the hive with its size in bytes.
+    pub fn beecells(&self) -> io::Result<Vec<(String, u64)>> {
+        let mut beecells = Vec::new();
+        for prefix in fs::read_dir(self.root.join(BEECELLS_DIR))? {
+            let prefix = prefix?;
+            if !prefix.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(prefix.path())? {
+                let entry = entry?;
+                let hash = entry.file_name().to_string_lossy().into_owned();
+                // Leftover temporary files aren't beecells
+                if entry.file_type()?.is_file() && hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    beecells.push((hash, entry.metadata()?.len()));
+                }
+            }
+        }
+        beecells.sort();
+        Ok(beecells)
+    }
+
+    fn cube_path(&self, cube_id: &str) -> io::Result<PathBuf> {
+        if cube_id.is_empty() || cube_id.contains(['/', '\\']) || cube_id.starts_with('.') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid cube id: {}", cube_id)));
+        }
+        Ok(self.root.join(CUBES_DIR).join(cube_id))
+    }
+
+    /// Size in bytes of everything stored for a cube.
+    pub fn cube_size(&self, cube_id: &str) -> io::Result<u64> {
+        let mut size = 0;
+        for entry in fs::read_dir(self.cube_path(cube_id)?)? {
+            size += entry?.metadata()?.len();
+        }
+        Ok(size)
+    }
+
+    pub fn remove_cube(&self, cube_id: &str) -> io::Result<()> {
+        match fs::remove_dir_all(self.cube_path(cube_id)?) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Lists the ids of every cube that has a directory in the hive.
+    pub fn cube_ids(&self) -> io::Result<Vec<String>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(self.root.join(CUBES_DIR))? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                ids.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Serializes `value` as JSON to `relative_path` under the hive root.
+    pub fn write_json<T: Serialize>(&self, relative_path: &Path, value: &T) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(value)?;
+        write_atomic(&self.root.join(relative_path), &json)
+    }
+
+    /// Reads a JSON document from `relative_path`, returning `None` if it doesn't exist.
+    pub fn read_json<T: DeserializeOwned>(&self, relative_path: &Path) -> io::Result<Option<T>> {
+        match fs::read(self.root.join(relative_path)) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn remove_json(&self, relative_path: &Path) -> io::Result<()> {
+        match fs::remove_file(self.root.join(relative_path)) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+// Numbers temporary files, so that threads writing the same file don't write over each other
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Write to a temporary file first and rename it into place, so readers never see a partial file.
+// The directory is synced as well, or the rename itself may not survive a crash.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    fs::create_dir_all(parent)?;
+    let tmp_path = path.with_extension(format!("tmp{}", TMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    fs::File::open(parent)?.sync_all()
+}
@@ -1,10 +1,26 @@
+pub mod acl;
+pub mod archive;
+pub mod asyncdatabase;
+pub mod catalog;
+pub mod chunker;
+pub mod codec;
+pub mod crypto;
 pub mod database;
+pub mod erasure;
+pub mod hive;
+pub mod listing;
 pub mod user;
 pub mod channel;
 pub mod message;
 pub mod app;
 pub mod login;
+pub mod merkle;
+pub mod namespace;
+pub mod query;
+pub mod quota;
 pub mod seigrconfig;
+pub mod snapshot;
+pub mod stream;
 pub mod ui;
 pub mod tui;
 pub mod eventhandler;
\ No newline at end of file
@@ -0,0 +1,226 @@
+use glob::{MatchOptions, Pattern};
+use regex::Regex;
+
+use crate::database::{DatabaseError, File};
+
+/// What [`Database::list_files`] sorts by. Ties are broken by name.
+///
+/// [`Database::list_files`]: crate::database::Database::list_files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortBy {
+    fn tag(self) -> &'static str {
+        match self {
+            SortBy::Name => "name",
+            SortBy::Size => "size",
+            SortBy::Modified => "modified",
+        }
+    }
+
+    fn key(self, file: &File) -> u64 {
+        match self {
+            SortBy::Name => 0,
+            SortBy::Size => file.size,
+            SortBy::Modified => file.modified,
+        }
+    }
+}
+
+/// Which files [`Database::list_files`] returns, in what order and how many at a time.
+///
+/// ```ignore
+/// let options = ListOptions::new().with_prefix("projects/").with_glob("**/*.log").sorted_by(SortBy::Size, true).with_limit(100);
+/// ```
+///
+/// [`Database::list_files`]: crate::database::Database::list_files
+#[derive(Debug, Default, Clone)]
+pub struct ListOptions {
+    pub prefix: String,
+    /// Matched against the whole path. `*` stays within a directory, `**` crosses them.
+    pub glob: Option<String>,
+    pub regex: Option<String>,
+    pub sort_by: SortBy,
+    pub descending: bool,
+    /// Most files in one page, all of them when `None`.
+    pub limit: Option<usize>,
+    /// Where the previous page ended, see [`FileListing::continuation`].
+    pub continuation: Option<String>,
+}
+
+impl ListOptions {
+    pub fn new() -> Self {
+        ListOptions::default()
+    }
+
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    pub fn with_glob(mut self, glob: &str) -> Self {
+        self.glob = Some(glob.to_string());
+        self
+    }
+
+    pub fn with_regex(mut self, regex: &str) -> Self {
+        self.regex = Some(regex.to_string());
+        self
+    }
+
+    pub fn sorted_by(mut self, sort_by: SortBy, descending: bool) -> Self {
+        self.sort_by = sort_by;
+        self.descending = descending;
+        self
+    }
+
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Continues a listing after the page that returned `token`.
+    pub fn with_continuation(mut self, token: &str) -> Self {
+        self.continuation = Some(token.to_string());
+        self
+    }
+}
+
+/// One page of [`Database::list_files`].
+///
+/// [`Database::list_files`]: crate::database::Database::list_files
+#[derive(Debug, Default, Clone)]
+pub struct FileListing {
+    pub files: Vec<File>,
+    /// Pass to [`ListOptions::with_continuation`] for the next page, `None` on the last one.
+    pub continuation: Option<String>,
+}
+
+// Filters compiled once per listing
+pub(crate) struct FileMatcher {
+    prefix: String,
+    glob: Option<Pattern>,
+    regex: Option<Regex>,
+}
+
+impl FileMatcher {
+    pub(crate) fn new(options: &ListOptions) -> Result<Self, DatabaseError> {
+        let glob = match &options.glob {
+            Some(glob) => Some(Pattern::new(glob).map_err(|error| DatabaseError::Other(format!("Invalid glob {}: {}", glob, error)))?),
+            None => None,
+        };
+        let regex = match &options.regex {
+            Some(regex) => Some(Regex::new(regex).map_err(|error| DatabaseError::Other(format!("Invalid regex {}: {}", regex, error)))?),
+            None => None,
+        };
+        Ok(FileMatcher {
+            prefix: options.prefix.clone(),
+            glob,
+            regex,
+        })
+    }
+
+    pub(crate) fn matches(&self, filename: &str) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        filename.starts_with(&self.prefix)
+            && self.glob.as_ref().is_none_or(|glob| glob.matches_with(filename, options))
+            && self.regex.as_ref().is_none_or(|regex| regex.is_match(filename))
+    }
+}
+
+/// Sorts `files` as asked and cuts out the page after the continuation token.
+pub(crate) fn paginate(mut files: Vec<File>, options: &ListOptions) -> Result<FileListing, DatabaseError> {
+    let sort_by = options.sort_by;
+    files.sort_by(|a, b| (sort_by.key(a), &a.filename).cmp(&(sort_by.key(b), &b.filename)));
+    if options.descending {
+        files.reverse();
+    }
+
+    // Tokens hold the sort key of the last file handed out, so files stored or deleted in the
+    // meantime don't shift the pages
+    if let Some(token) = &options.continuation {
+        let (key, filename) = decode_token(token, options)?;
+        let after = |file: &File| {
+            let position = (sort_by.key(file), file.filename.as_str()).cmp(&(key, filename.as_str()));
+            if options.descending { position.is_lt() } else { position.is_gt() }
+        };
+        files.retain(after);
+    }
+
+    let mut continuation = None;
+    if let Some(limit) = options.limit {
+        if files.len() > limit {
+            files.truncate(limit);
+            continuation = files.last().map(|last| encode_token(last, options));
+        }
+    }
+    Ok(FileListing { files, continuation })
+}
+
+fn encode_token(file: &File, options: &ListOptions) -> String {
+    let direction = if options.descending { "desc" } else { "asc" };
+    let token = format!("{}:{}:{}:{}", options.sort_by.tag(), direction, options.sort_by.key(file), file.filename);
+    hex::encode(token)
+}
+
+fn decode_token(token: &str, options: &ListOptions) -> Result<(u64, String), DatabaseError> {
+    let invalid = || DatabaseError::Other(format!("Invalid continuation token: {}", token));
+    let decoded = String::from_utf8(hex::decode(token).map_err(|_| invalid())?).map_err(|_| invalid())?;
+    let mut parts = decoded.splitn(4, ':');
+    let (Some(tag), Some(direction), Some(key), Some(filename)) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    // A token only continues the listing it came from
+    let direction_matches = (direction == "desc") == options.descending;
+    if tag != options.sort_by.tag() || !direction_matches {
+        return Err(invalid());
+    }
+    Ok((key.parse().map_err(|_| invalid())?, filename.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(filename: &str, size: u64) -> File {
+        File {
+            filename: filename.to_string(),
+            size,
+            ..File::default()
+        }
+    }
+
+    #[test]
+    fn test_pages_continue_where_the_last_one_ended() {
+        let files = vec![file("c", 10), file("a", 30), file("b", 10), file("d", 20)];
+        let options = ListOptions::new().sorted_by(SortBy::Size, true).with_limit(3);
+        let first = paginate(files.clone(), &options).unwrap();
+        assert_eq!(first.files.iter().map(|file| file.filename.as_str()).collect::<Vec<_>>(), vec!["a", "d", "c"]);
+
+        let token = first.continuation.unwrap();
+        let second = paginate(files.clone(), &options.clone().with_continuation(&token)).unwrap();
+        assert_eq!(second.files.iter().map(|file| file.filename.as_str()).collect::<Vec<_>>(), vec!["b"]);
+        assert!(second.continuation.is_none());
+
+        assert!(paginate(files.clone(), &ListOptions::new().with_continuation(&token)).is_err());
+        assert!(paginate(files, &ListOptions::new().with_continuation("zz")).is_err());
+    }
+
+    #[test]
+    fn test_filters_are_combined() {
+        let matcher = FileMatcher::new(&ListOptions::new().with_prefix("logs/").with_glob("logs/*.log").with_regex("20\\d\\d")).unwrap();
+        assert!(matcher.matches("logs/2024.log"));
+        assert!(!matcher.matches("logs/old/2024.log"));
+        assert!(!matcher.matches("logs/latest.log"));
+        assert!(!matcher.matches("data/2024.log"));
+        assert!(FileMatcher::new(&ListOptions::new().with_regex("(")).is_err());
+    }
+}
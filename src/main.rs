@@ -1,6 +1,4 @@
 use seigr_hive::app;
-use seigr_hive::login;
-use seigr_hive::ui;
 use seigr_hive::tui;
 use seigr_hive::user;
 use seigr_hive::seigrconfig;
@@ -11,15 +9,13 @@ pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 use std::path::Path;
 use std::fs;
 use app::{App, AppState};
-use login::{ authenticate_user, config_exists};
-use seigr_hive::{seigrconfig::generate_key, database::{Database, DatabaseError}};
+use seigr_hive::{seigrconfig::{generate_key, generate_nonce}, database::Database};
 use seigrconfig::SeigrConfig;
-use ratatui::{backend::{CrosstermBackend, Backend}, Terminal, Frame};
+use ratatui::{backend::CrosstermBackend, Terminal};
 use tokio::io;
 use tui::Tui;
 use eventhandler::EventHandler;
-use seigr_hive::ui::{render_login, render_register};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use rayon::ThreadPoolBuilder;
 
 // Show a welcoming message when the application starts.
@@ -37,27 +33,6 @@ use crate::user::User;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Create a thread pool
-    let pool = ThreadPoolBuilder::new().num_threads(4).build().expect("Failed to create thread pool");
-
-    // Wrap the Database in an Arc<Mutex<>> so it can be shared between threads
-    let database = Arc::new(Mutex::new(Database::new()));
-
-    // Handle a request
-    pool.install(|| {
-        // Lock the database
-        let mut database = match database.lock() {
-            Ok(database) => database,
-            Err(_) => return, // Handle the error appropriately
-        };
-
-        // Use the database
-        let data = match database.retrieve_file("example.txt") {
-            Ok(data) => data,
-            Err(_) => return, // Handle the error appropriately
-        };
-    });
-    
     // Load or generate a key
     let key = if Path::new("keyfile").exists() {
         let mut array = [0; 32];
@@ -70,13 +45,37 @@ async fn main() -> Result<()> {
         key
     };
 
+    // Load or generate the nonce the config is encrypted with
+    let nonce = if Path::new("noncefile").exists() {
+        let mut array = [0; 12];
+        let vec = fs::read("noncefile").expect("Failed to read nonce");
+        array.copy_from_slice(&vec);
+        array
+    } else {
+        let nonce = generate_nonce().expect("Failed to generate nonce");
+        fs::write("noncefile", nonce).expect("Failed to write nonce");
+        nonce
+    };
+
+    // The Database synchronizes itself, so it is shared between threads without a lock around it
+    let database = Arc::new(Database::new("hive", &key, &nonce)?);
+
+    // Create a thread pool
+    let pool = ThreadPoolBuilder::new().num_threads(4).build().expect("Failed to create thread pool");
+
+    // Check the hive on the pool, the beecells are read in parallel
+    let report = pool.install(|| database.scrub())?;
+    if !report.is_clean() {
+        println!("The hive has {} damaged beecells", report.issues.len());
+    }
+
     // Show a welcoming message
     welcome();
 
     println!("Before creating SeigrConfig");
     
-    // Load or create the SeigrConfig
-    let mut config = SeigrConfig::new(&key).expect("Failed to load or create config");
+    // Load the SeigrConfig the database takes its users from, so both see the same users
+    let mut config = SeigrConfig::new(&database.config_path().to_string_lossy(), &key, &nonce).expect("Failed to load or create config");
     config.load_users()?;
 
     println!("After creating SeigrConfig");
@@ -96,8 +95,7 @@ async fn main() -> Result<()> {
         let user_input = get_user_input()?;
         // Add the new user to the database
         let user = User::new(user_input.0.clone(), user_input.1.clone(), user_input.2.clone())?;
-        let mut database_guard = database.lock().map_err(|_| DatabaseError::LockFailed)?;
-        database_guard.add_user(user)?;
+        database.add_user(user)?;
         user_input
     } else {
         // If the config has users, get the username from the user input
@@ -105,8 +103,7 @@ async fn main() -> Result<()> {
         let username = reader.next_line().await?.ok_or(io::Error::new(io::ErrorKind::InvalidInput, "No input provided"))?;
     
         // Retrieve the user from the database
-        let database_guard = database.lock().map_err(|_| DatabaseError::LockFailed)?;
-        let user = (*database_guard).get_user(username.clone())?;
+        let user = database.get_user(username.clone())?;
         (username, user.email.clone(), user.password.clone())
     };
     
@@ -114,8 +111,7 @@ async fn main() -> Result<()> {
     app.set_user(User::new(username.clone(), password.to_string(), email.to_string())?);
 
     // Check if the user exists in the database
-    let database_guard = database.lock().map_err(|_| DatabaseError::LockFailed)?;
-    let user_exists = (*database_guard).user_exists(&username)?;
+    let user_exists = database.user_exists(&username)?;
     if !user_exists {
         // Now you can call draw_register on your Tui instance
         tui.draw_register(&mut app)?;
@@ -124,8 +120,8 @@ async fn main() -> Result<()> {
         tui.draw_login(&mut app)?;
     }
 
-    // Save the config to file
-    config.save_config()?;
+    // Save the config back where it was loaded from
+    config.save_config_to(database.config_path(), &key)?;
 
     Ok(())
 }
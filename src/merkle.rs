@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+
+use crate::database::calculate_hash;
+
+// Leaves and inner nodes are hashed with different prefixes, so a node can't pose as a leaf
+const LEAF_PREFIX: &[u8] = b"\x00";
+const NODE_PREFIX: &[u8] = b"\x01";
+
+fn leaf_hash(leaf: &str) -> String {
+    calculate_hash(&[LEAF_PREFIX, leaf.as_bytes()].concat())
+}
+
+fn node_hash(left: &str, right: &str) -> String {
+    calculate_hash(&[NODE_PREFIX, left.as_bytes(), right.as_bytes()].concat())
+}
+
+// Hashes one level of the tree into the next. An odd node out is carried up as is.
+fn next_level(level: &[String]) -> Vec<String> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(left, right),
+            [single] => single.clone(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Computes the Merkle root over `leaves`, which are hex hashes themselves.
+pub fn merkle_root(leaves: &[String]) -> String {
+    if leaves.is_empty() {
+        return leaf_hash("");
+    }
+    let mut level: Vec<String> = leaves.iter().map(|leaf| leaf_hash(leaf)).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// One sibling on the path from a leaf up to the root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub sibling: String,
+    pub side: Side,
+}
+
+/// Lists the siblings needed to recompute the root from `leaves[index]`.
+pub fn merkle_proof(leaves: &[String], index: usize) -> Option<Vec<ProofStep>> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut steps = Vec::new();
+    let mut level: Vec<String> = leaves.iter().map(|leaf| leaf_hash(leaf)).collect();
+    let mut index = index;
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            let side = if sibling < index { Side::Left } else { Side::Right };
+            steps.push(ProofStep { sibling: level[sibling].clone(), side });
+        }
+        level = next_level(&level);
+        index /= 2;
+    }
+    Some(steps)
+}
+
+/// Checks that `leaf` hashes up to `root` through `steps`.
+pub fn verify_proof(leaf: &str, steps: &[ProofStep], root: &str) -> bool {
+    let mut hash = leaf_hash(leaf);
+    for step in steps {
+        hash = match step.side {
+            Side::Left => node_hash(&step.sibling, &hash),
+            Side::Right => node_hash(&hash, &step.sibling),
+        };
+    }
+    hash == root
+}
+
+/// Proves that one beecell is part of a file, from the beecell hash up to the file root.
+///
+/// The beecell hash leads to its frame root, the frame root to its cube root and the cube root
+/// to the root over all cubes of the file, see [`Database::prove_beecell`].
+///
+/// [`Database::prove_beecell`]: crate::database::Database::prove_beecell
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub beecell_hash: String,
+    pub frame_proof: Vec<ProofStep>,
+    pub frame_root: String,
+    pub cube_proof: Vec<ProofStep>,
+    pub cube_root: String,
+    pub file_proof: Vec<ProofStep>,
+}
+
+impl InclusionProof {
+    /// Checks that `data` is the proven beecell and that it belongs to the file with `file_root`.
+    pub fn verify(&self, data: &[u8], file_root: &str) -> bool {
+        calculate_hash(data) == self.beecell_hash
+            && verify_proof(&self.beecell_hash, &self.frame_proof, &self.frame_root)
+            && verify_proof(&self.frame_root, &self.cube_proof, &self.cube_root)
+            && verify_proof(&self.cube_root, &self.file_proof, file_root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<String> {
+        (0..count).map(|i| calculate_hash(&[i as u8])).collect()
+    }
+
+    #[test]
+    fn test_every_leaf_proves_against_the_root() {
+        for count in 1..10 {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = merkle_proof(&leaves, index).unwrap();
+                assert!(verify_proof(leaf, &proof, &root), "leaf {} of {}", index, count);
+            }
+        }
+    }
+
+    #[test]
+    fn test_proof_fails_for_another_leaf() {
+        let leaves = leaves(5);
+        let root = merkle_root(&leaves);
+        let proof = merkle_proof(&leaves, 2).unwrap();
+        assert!(!verify_proof(&leaves[3], &proof, &root));
+        assert!(merkle_proof(&leaves, 5).is_none());
+    }
+}
@@ -0,0 +1,100 @@
+use crate::database::DatabaseError;
+
+/// Whether a [`DirEntry`] is a file or a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EntryKind {
+    Directory,
+    File,
+}
+
+/// One entry of a directory listing, see [`Database::list_dir`].
+///
+/// [`Database::list_dir`]: crate::database::Database::list_dir
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// Last component of the path.
+    pub name: String,
+    /// Full, normalized path from the root of the hive.
+    pub path: String,
+    pub kind: EntryKind,
+    /// Size of the current version for files, 0 for directories.
+    pub size: u64,
+}
+
+/// Brings a path into the form filenames are stored in.
+///
+/// Components are separated by `/`. Empty components and `.` are dropped and `..` goes up one
+/// directory, so `/projects//hive/./docs/../notes.txt` becomes `projects/hive/notes.txt`. The
+/// root of the hive is the empty path. Going above the root, backslashes and NUL characters are
+/// rejected.
+pub fn normalize_path(path: &str) -> Result<String, DatabaseError> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                if components.pop().is_none() {
+                    return Err(DatabaseError::InvalidPath(path.to_string()));
+                }
+            }
+            _ if component.contains(['\\', '\0']) => return Err(DatabaseError::InvalidPath(path.to_string())),
+            _ => components.push(component),
+        }
+    }
+    Ok(components.join("/"))
+}
+
+/// Like [`normalize_path`], but the root isn't a valid filename.
+pub(crate) fn normalize_filename(filename: &str) -> Result<String, DatabaseError> {
+    let normalized = normalize_path(filename)?;
+    if normalized.is_empty() {
+        return Err(DatabaseError::InvalidPath(filename.to_string()));
+    }
+    Ok(normalized)
+}
+
+/// Every directory above `path`, outermost first: `a/b/c` gives `a` and `a/b`.
+pub(crate) fn parent_dirs(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/').map(move |(index, _)| &path[..index])
+}
+
+/// Directory `path` is in, the empty path for the root.
+pub(crate) fn parent_dir(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/// Last component of `path`.
+pub(crate) fn base_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Whether `path` lies somewhere below the directory `dir`.
+pub(crate) fn is_within(path: &str, dir: &str) -> bool {
+    dir.is_empty() || (path.len() > dir.len() && path.starts_with(dir) && path.as_bytes()[dir.len()] == b'/')
+}
+
+/// Moves `path`, which is `from` or lies below it, to the same place below `to`.
+pub(crate) fn rebase(path: &str, from: &str, to: &str) -> String {
+    format!("{}{}", to, &path[from.len()..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_are_normalized() {
+        assert_eq!(normalize_path("/projects//hive/./docs/../notes.txt").unwrap(), "projects/hive/notes.txt");
+        assert_eq!(normalize_path("a/b/").unwrap(), "a/b");
+        assert_eq!(normalize_path("/").unwrap(), "");
+        assert!(matches!(normalize_path("a/../../b"), Err(DatabaseError::InvalidPath(_))));
+        assert!(normalize_path("a\\b").is_err());
+        assert!(normalize_filename("./").is_err());
+
+        assert_eq!(parent_dirs("a/b/c").collect::<Vec<_>>(), vec!["a", "a/b"]);
+        assert_eq!((parent_dir("a/b/c"), parent_dir("a")), ("a/b", ""));
+        assert_eq!(base_name("a/b/c"), "c");
+        assert!(is_within("a/b", "a") && is_within("a", "") && !is_within("ab", "a") && !is_within("a", "a"));
+        assert_eq!(rebase("a/b/c", "a/b", "x"), "x/c");
+    }
+}
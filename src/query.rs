@@ -0,0 +1,388 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+
+use crate::database::{DatabaseError, File};
+
+/// Files by tag and by metadata value, kept in the catalog so queries don't scan every file.
+///
+/// Terms are `tag:<tag>` and `meta:<key>=<value>`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct MetadataIndex {
+    terms: BTreeMap<String, BTreeSet<String>>,
+}
+
+pub(crate) fn tag_term(tag: &str) -> String {
+    format!("tag:{}", tag)
+}
+
+pub(crate) fn metadata_term(key: &str, value: &str) -> String {
+    format!("meta:{}={}", key, value)
+}
+
+impl MetadataIndex {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    pub(crate) fn insert(&mut self, term: String, filename: &str) {
+        self.terms.entry(term).or_default().insert(filename.to_string());
+    }
+
+    pub(crate) fn remove(&mut self, term: &str, filename: &str) {
+        if let Some(filenames) = self.terms.get_mut(term) {
+            filenames.remove(filename);
+            if filenames.is_empty() {
+                self.terms.remove(term);
+            }
+        }
+    }
+
+    fn lookup(&self, term: &str) -> BTreeSet<String> {
+        self.terms.get(term).cloned().unwrap_or_default()
+    }
+}
+
+/// How a [`Condition`] compares a field with its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// One `field<op>value` term of a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub field: String,
+    pub comparison: Comparison,
+    pub value: String,
+}
+
+/// A parsed query, see [`Query::parse`] for the syntax.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Condition(Condition),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+}
+
+impl Query {
+    /// Parses a query such as `tag:release AND size>10MB AND NOT channel:beta`.
+    ///
+    /// Terms are `field:value` or `field` with one of `=`, `!=`, `<`, `<=`, `>`, `>=` and a value,
+    /// combined with `AND`, `OR`, `NOT` and parentheses. `AND` binds tighter than `OR`, and terms
+    /// next to each other are ANDed. Values with spaces go in double quotes.
+    ///
+    /// The fields are `tag`, `name` (a glob over the path), `owner`, `type` (the MIME type),
+    /// `hash`, `size` (with an optional `KB`, `MB`, `GB`, `KiB`, `MiB` or `GiB` suffix),
+    /// `created`, `modified` (seconds since the Unix epoch) and `version`. Any other field is a
+    /// metadata key. Values that are numbers on both sides are ordered as numbers.
+    pub fn parse(query: &str) -> Result<Query, DatabaseError> {
+        let tokens = tokenize(query)?;
+        let mut parser = Parser { tokens: &tokens, position: 0 };
+        let parsed = parser.or()?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(parsed),
+            Some(token) => Err(DatabaseError::InvalidQuery(format!("unexpected {:?}", token))),
+        }
+    }
+
+    /// The files the index can tell match, or `None` if the query needs every file looked at.
+    /// The result may hold too many files, but never misses one.
+    pub(crate) fn candidates(&self, index: &MetadataIndex) -> Option<BTreeSet<String>> {
+        match self {
+            Query::Condition(condition) => {
+                let equal = matches!(condition.comparison, Comparison::Equal);
+                match condition.field.as_str() {
+                    "tag" if equal => Some(index.lookup(&tag_term(&condition.value))),
+                    field if equal && !is_file_field(field) => {
+                        Some(index.lookup(&metadata_term(field, &condition.value)))
+                    }
+                    _ => None,
+                }
+            }
+            Query::And(left, right) => match (left.candidates(index), right.candidates(index)) {
+                (Some(left), Some(right)) => Some(left.intersection(&right).cloned().collect()),
+                (Some(one), None) | (None, Some(one)) => Some(one),
+                (None, None) => None,
+            },
+            Query::Or(left, right) => match (left.candidates(index), right.candidates(index)) {
+                (Some(left), Some(right)) => Some(left.union(&right).cloned().collect()),
+                _ => None,
+            },
+            Query::Not(_) => None,
+        }
+    }
+
+    pub(crate) fn matches(&self, file: &File) -> Result<bool, DatabaseError> {
+        Ok(match self {
+            Query::Condition(condition) => condition.matches(file)?,
+            Query::And(left, right) => left.matches(file)? && right.matches(file)?,
+            Query::Or(left, right) => left.matches(file)? || right.matches(file)?,
+            Query::Not(query) => !query.matches(file)?,
+        })
+    }
+}
+
+fn is_file_field(field: &str) -> bool {
+    matches!(field, "tag" | "name" | "owner" | "type" | "hash" | "size" | "created" | "modified" | "version")
+}
+
+impl Condition {
+    fn matches(&self, file: &File) -> Result<bool, DatabaseError> {
+        let number = |value: u64| Some(value.to_string());
+        let actual = match self.field.as_str() {
+            "tag" => {
+                let found = file.tags.contains(&self.value);
+                return Ok(if self.comparison == Comparison::NotEqual { !found } else { found });
+            }
+            "name" => {
+                let pattern = Pattern::new(&self.value).map_err(|error| DatabaseError::InvalidQuery(format!("{}: {}", self.value, error)))?;
+                let found = pattern.matches(&file.filename);
+                return Ok(if self.comparison == Comparison::NotEqual { !found } else { found });
+            }
+            "size" => {
+                let limit = parse_size(&self.value)?;
+                return Ok(compare(file.size.cmp(&limit), self.comparison));
+            }
+            "owner" => Some(file.beeid.clone()),
+            "type" => Some(file.mime_type.clone()),
+            "hash" => Some(file.content_hash.clone()),
+            "created" => number(file.created),
+            "modified" => number(file.modified),
+            "version" => number(file.version),
+            key => file.attributes.get(key).cloned(),
+        };
+        let Some(actual) = actual else {
+            // A file without the key only matches when asking for a different value
+            return Ok(self.comparison == Comparison::NotEqual);
+        };
+        // Equality is on the text, the way the index looks values up
+        let ordering = match (self.comparison, actual.parse::<f64>(), self.value.parse::<f64>()) {
+            (Comparison::Equal | Comparison::NotEqual, _, _) => actual.as_str().cmp(self.value.as_str()),
+            (_, Ok(actual), Ok(expected)) => actual.partial_cmp(&expected).unwrap_or(std::cmp::Ordering::Equal),
+            _ => actual.as_str().cmp(self.value.as_str()),
+        };
+        Ok(compare(ordering, self.comparison))
+    }
+}
+
+fn compare(ordering: std::cmp::Ordering, comparison: Comparison) -> bool {
+    match comparison {
+        Comparison::Equal => ordering.is_eq(),
+        Comparison::NotEqual => ordering.is_ne(),
+        Comparison::Less => ordering.is_lt(),
+        Comparison::LessOrEqual => ordering.is_le(),
+        Comparison::Greater => ordering.is_gt(),
+        Comparison::GreaterOrEqual => ordering.is_ge(),
+    }
+}
+
+/// Parses `10MB`, `1.5GiB`, `512` and the like into bytes.
+pub(crate) fn parse_size(value: &str) -> Result<u64, DatabaseError> {
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(DatabaseError::InvalidQuery(format!("unknown size unit in {}", value))),
+    };
+    let number: f64 = number.parse().map_err(|_| DatabaseError::InvalidQuery(format!("invalid size {}", value)))?;
+    Ok((number * multiplier as f64) as u64)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Condition(Condition),
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, DatabaseError> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            _ => {
+                // A word runs up to whitespace or a parenthesis outside of quotes
+                let mut word = String::new();
+                let mut quoted = false;
+                while let Some(&c) = chars.peek() {
+                    if !quoted && (c.is_whitespace() || c == '(' || c == ')') {
+                        break;
+                    }
+                    if c == '"' {
+                        quoted = !quoted;
+                    } else {
+                        word.push(c);
+                    }
+                    chars.next();
+                }
+                if quoted {
+                    return Err(DatabaseError::InvalidQuery("unterminated quote".to_string()));
+                }
+                tokens.push(match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Condition(parse_condition(&word)?),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_condition(word: &str) -> Result<Condition, DatabaseError> {
+    const OPERATORS: [(&str, Comparison); 7] = [
+        (">=", Comparison::GreaterOrEqual),
+        ("<=", Comparison::LessOrEqual),
+        ("!=", Comparison::NotEqual),
+        (">", Comparison::Greater),
+        ("<", Comparison::Less),
+        ("=", Comparison::Equal),
+        (":", Comparison::Equal),
+    ];
+    // The operator is whichever comes first, trying the two-character ones before the others
+    let found = OPERATORS
+        .iter()
+        .filter_map(|(operator, comparison)| word.find(operator).map(|index| (index, *operator, *comparison)))
+        .min_by_key(|(index, _, _)| *index);
+    match found {
+        Some((index, operator, comparison)) if index > 0 => Ok(Condition {
+            field: word[..index].to_string(),
+            comparison,
+            value: word[index + operator.len()..].to_string(),
+        }),
+        _ => Err(DatabaseError::InvalidQuery(format!("expected field and value in {}", word))),
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn next_is(&self, token: &Token) -> bool {
+        self.tokens.get(self.position) == Some(token)
+    }
+
+    fn or(&mut self) -> Result<Query, DatabaseError> {
+        let mut query = self.and()?;
+        while self.next_is(&Token::Or) {
+            self.position += 1;
+            query = Query::Or(Box::new(query), Box::new(self.and()?));
+        }
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query, DatabaseError> {
+        let mut query = self.not()?;
+        loop {
+            if self.next_is(&Token::And) {
+                self.position += 1;
+            } else if !matches!(self.tokens.get(self.position), Some(Token::Not | Token::Open | Token::Condition(_))) {
+                return Ok(query);
+            }
+            query = Query::And(Box::new(query), Box::new(self.not()?));
+        }
+    }
+
+    fn not(&mut self) -> Result<Query, DatabaseError> {
+        if self.next_is(&Token::Not) {
+            self.position += 1;
+            return Ok(Query::Not(Box::new(self.not()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Query, DatabaseError> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        match token {
+            Some(Token::Open) => {
+                let query = self.or()?;
+                if !self.next_is(&Token::Close) {
+                    return Err(DatabaseError::InvalidQuery("missing closing parenthesis".to_string()));
+                }
+                self.position += 1;
+                Ok(query)
+            }
+            Some(Token::Condition(condition)) => Ok(Query::Condition(condition)),
+            Some(token) => Err(DatabaseError::InvalidQuery(format!("unexpected {:?}", token))),
+            None => Err(DatabaseError::InvalidQuery("unexpected end of query".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(field: &str, comparison: Comparison, value: &str) -> Query {
+        Query::Condition(Condition {
+            field: field.to_string(),
+            comparison,
+            value: value.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_queries_parse_with_precedence() {
+        let query = Query::parse(r#"tag:release size>10MB OR NOT (channel="early access" AND build>=100)"#).unwrap();
+        let release = Query::And(Box::new(condition("tag", Comparison::Equal, "release")), Box::new(condition("size", Comparison::Greater, "10MB")));
+        let early = Query::And(
+            Box::new(condition("channel", Comparison::Equal, "early access")),
+            Box::new(condition("build", Comparison::GreaterOrEqual, "100")),
+        );
+        assert_eq!(query, Query::Or(Box::new(release), Box::new(Query::Not(Box::new(early)))));
+
+        assert!(Query::parse("tag:release AND").is_err());
+        assert!(Query::parse("(tag:release").is_err());
+        assert!(Query::parse("release").is_err());
+        assert_eq!(parse_size("1.5KiB").unwrap(), 1536);
+        assert!(parse_size("10 parsecs").is_err());
+    }
+
+    #[test]
+    fn test_conditions_compare_numbers_and_text() {
+        let mut file = File {
+            filename: "builds/app.tar".to_string(),
+            size: 12_000_000,
+            beeid: "seigr_bee1".to_string(),
+            ..File::default()
+        };
+        file.attributes.insert("build".to_string(), "120".to_string());
+        file.tags.insert("release".to_string());
+        let matches = |query: &str| Query::parse(query).unwrap().matches(&file).unwrap();
+
+        assert!(matches("tag:release AND size>10MB AND owner:seigr_bee1"));
+        assert!(matches("build>99 name:builds/*.tar"));
+        assert!(!matches("build<99 OR tag:nightly"));
+        assert!(matches("channel!=beta NOT tag:nightly"));
+    }
+}
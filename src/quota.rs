@@ -0,0 +1,133 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use crate::catalog::{Catalog, FileVersion};
+use crate::database::{Cube, Frame};
+
+/// How much one user stores, see [`Database::usage`].
+///
+/// Every version of every file the user owns counts. A beecell shared by files of several users
+/// is split evenly between them, so deduplication lowers everyone's usage and the usage of all
+/// users adds up to what their files take up in the hive. Files without an owner and beecells
+/// only kept by snapshots aren't charged to anyone.
+///
+/// [`Database::usage`]: crate::database::Database::usage
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UsageReport {
+    pub beeid: String,
+    pub files: usize,
+    pub versions: usize,
+    /// What the versions add up to before deduplication and compression.
+    pub logical_bytes: u64,
+    /// The user's share of the stored beecells and parity shards, what quotas are checked against.
+    pub stored_bytes: u64,
+    pub quota: Option<u64>,
+}
+
+/// The usage of every user owning a file, without quotas filled in.
+///
+/// It is kept up to date as the catalog changes, see [`Catalog::apply`]: every file is charged to
+/// its owner when it gets one or a new version, and taken off again before it changes.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct UsageIndex {
+    reports: BTreeMap<String, UsageReport>,
+    // Size of every stored object a file with an owner refers to, and how many references each
+    // owner has to it
+    objects: HashMap<String, (u64, BTreeMap<String, u64>)>,
+}
+
+impl UsageIndex {
+    pub(crate) fn build(catalog: &Catalog, cubes: &HashMap<String, Arc<Cube>>) -> Self {
+        let mut usage = UsageIndex::default();
+        for (filename, acl) in &catalog.acls {
+            // Directories have owners too, but take up nothing
+            if let Some(versions) = catalog.versions.get(filename) {
+                usage.charge(&acl.owner, versions, cubes, true);
+            }
+        }
+        usage
+    }
+
+    pub(crate) fn reports(&self) -> &BTreeMap<String, UsageReport> {
+        &self.reports
+    }
+
+    /// Adds a file with `versions` to the usage of `owner`, or takes it off again.
+    pub(crate) fn charge(&mut self, owner: &str, versions: &[FileVersion], cubes: &HashMap<String, Arc<Cube>>, add: bool) {
+        let report = self.reports.entry(owner.to_string()).or_insert_with(|| UsageReport {
+            beeid: owner.to_string(),
+            ..UsageReport::default()
+        });
+        let logical_bytes: u64 = versions.iter().map(|version| version.size).sum();
+        if add {
+            report.files += 1;
+            report.versions += versions.len();
+            report.logical_bytes += logical_bytes;
+        } else {
+            report.files = report.files.saturating_sub(1);
+            report.versions = report.versions.saturating_sub(versions.len());
+            report.logical_bytes = report.logical_bytes.saturating_sub(logical_bytes);
+        }
+
+        for version in versions {
+            let frames = version.cube_ids.iter().filter_map(|cube_id| cubes.get(cube_id)).flat_map(|cube| &cube.frames);
+            for (_, address, size) in frames.flat_map(Frame::objects) {
+                self.reference(owner, address, size, add);
+            }
+        }
+        if self.reports.get(owner).is_some_and(|report| report.files == 0) {
+            self.reports.remove(owner);
+        }
+    }
+
+    // Counts one more or one less reference of `owner` to an object. Its bytes are split anew
+    // whenever that makes the owner start or stop sharing it.
+    fn reference(&mut self, owner: &str, address: &str, size: u64, add: bool) {
+        let UsageIndex { reports, objects } = self;
+        let (size, owners) = match objects.get_mut(address) {
+            Some(object) => object,
+            None if add => objects.entry(address.to_string()).or_insert((size, BTreeMap::new())),
+            None => return,
+        };
+        match (owners.get_mut(owner), add) {
+            (Some(count), true) => {
+                *count += 1;
+                return;
+            }
+            (Some(count), false) if *count > 1 => {
+                *count -= 1;
+                return;
+            }
+            (None, false) => return,
+            _ => {}
+        }
+        let before: Vec<String> = owners.keys().cloned().collect();
+        if add {
+            owners.insert(owner.to_string(), 1);
+        } else {
+            owners.remove(owner);
+        }
+
+        for (beeid, share) in shares(*size, &before) {
+            if let Some(report) = reports.get_mut(beeid) {
+                report.stored_bytes = report.stored_bytes.saturating_sub(share);
+            }
+        }
+        for (beeid, share) in shares(*size, owners.keys()) {
+            if let Some(report) = reports.get_mut(beeid) {
+                report.stored_bytes += share;
+            }
+        }
+        if owners.is_empty() {
+            objects.remove(address);
+        }
+    }
+}
+
+// Splits `size` bytes between sorted `owners`. The bytes that don't divide evenly go to the first
+// owners, one each.
+fn shares<'a>(size: u64, owners: impl IntoIterator<Item = &'a String, IntoIter: ExactSizeIterator>) -> impl Iterator<Item = (&'a String, u64)> {
+    let owners = owners.into_iter();
+    let count = owners.len() as u64;
+    owners.enumerate().map(move |(index, owner)| (owner, size / count + u64::from((index as u64) < size % count)))
+}
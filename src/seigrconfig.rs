@@ -7,6 +7,8 @@ use serde::{Deserialize, Serialize};
 use ring::aead::{self, Aad, BoundKey, UnboundKey, LessSafeKey};
 use ring::rand::{SecureRandom, SystemRandom};
 
+use crate::crypto::random_bytes;
+use crate::hive::write_atomic;
 use crate::user::User;
 use toml;
 
@@ -31,6 +33,9 @@ pub struct SeigrConfig {
     key: [u8; KEY_LENGTH],
     nonce: [u8; NONCE_LENGTH],
     password_hash: Option<String>,
+    // Quota of users that don't have their own, unlimited when `None`
+    #[serde(default)]
+    pub default_quota: Option<u64>,
 }
 
 impl SeigrConfig {
@@ -49,12 +54,15 @@ impl SeigrConfig {
             Ok(config)
         } else {
             // If the config file exists, read it
-            Self::read_config(key)
+            Self::read_config_from(config_path, key)
         }
     }
     
     pub fn read_config(key: &[u8; KEY_LENGTH]) -> io::Result<SeigrConfig> {
-        let config_path = Path::new(CONFIG_FILE_PATH);
+        Self::read_config_from(Path::new(CONFIG_FILE_PATH), key)
+    }
+
+    pub fn read_config_from(config_path: &Path, key: &[u8; KEY_LENGTH]) -> io::Result<SeigrConfig> {
         let mut config_file = fs::File::open(config_path)?;
         let mut encrypted_config = Vec::new();
         config_file.read_to_end(&mut encrypted_config)?;
@@ -75,6 +83,14 @@ impl SeigrConfig {
         Ok(())
     }
 
+    /// Encrypts the config with `key` and writes it to `config_path`, where [`SeigrConfig::new`]
+    /// reads it back from.
+    pub fn save_config_to(&self, config_path: &Path, key: &[u8; KEY_LENGTH]) -> io::Result<()> {
+        let nonce: [u8; NONCE_LENGTH] = random_bytes().map_err(io::Error::other)?;
+        let encrypted_config = encrypt_config(self, key, &nonce)?;
+        write_atomic(config_path, &encrypted_config)
+    }
+
     // Getters and setters for username, password, email, bee_id, and other options
     pub fn username(&self) -> &str {
         &self.username
@@ -108,6 +124,7 @@ impl SeigrConfig {
             key: [0u8; KEY_LENGTH],
             nonce: [0u8; NONCE_LENGTH],
             password_hash: None,
+            default_quota: None,
         }
     }
 
@@ -241,7 +258,7 @@ pub fn generate_key() -> Result<[u8; KEY_LENGTH], ring::error::Unspecified> {
     Ok(key)
 }
 
-fn generate_nonce() -> Result<[u8; NONCE_LENGTH], ring::error::Unspecified> {
+pub fn generate_nonce() -> Result<[u8; NONCE_LENGTH], ring::error::Unspecified> {
     let rng = SystemRandom::new();
     let mut nonce = [0u8; NONCE_LENGTH];
     rng.fill(&mut nonce)?;
@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::acl::Acl;
+use crate::crypto::{DataKey, WrappedKey};
+use crate::database::{read_cubes, Cube, DatabaseError};
+use crate::hive::HiveStore;
+use crate::stream::FileReader;
+
+/// A named, read-only copy of every file link at one point in time.
+///
+/// Snapshots hold references to the cubes they pin, so their beecells stay in the hive until the
+/// snapshot is deleted, however the files change afterwards.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Beeid of the user who took it, empty if nobody was signed in.
+    #[serde(default)]
+    pub owner: String,
+    pub(crate) files: BTreeMap<String, SnapshotFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SnapshotFile {
+    pub(crate) cube_ids: Vec<String>,
+    #[serde(default)]
+    pub(crate) key: Option<WrappedKey>,
+    // Access to the file as it was when the snapshot was taken
+    #[serde(default)]
+    pub(crate) acl: Option<Acl>,
+    // Hash of the content, empty if the version didn't record one
+    #[serde(default)]
+    pub(crate) content_hash: String,
+}
+
+impl SnapshotFile {
+    // Cubes are named after their content, so the same ids mean the same bytes. An encrypted file
+    // stored again gets a new data key and so new cubes, only its content hash tells it is the same.
+    fn same_content(&self, other: &SnapshotFile) -> bool {
+        let hashed = !self.content_hash.is_empty() && !other.content_hash.is_empty();
+        self.cube_ids == other.cube_ids || (hashed && self.content_hash == other.content_hash)
+    }
+}
+
+impl Snapshot {
+    /// Names of the files in the snapshot, sorted.
+    pub fn filenames(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+/// What changed between two snapshots, see [`Database::diff_snapshots`].
+///
+/// [`Database::diff_snapshots`]: crate::database::Database::diff_snapshots
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+impl SnapshotDiff {
+    pub(crate) fn between(from: &Snapshot, to: &Snapshot) -> Self {
+        let mut diff = SnapshotDiff::default();
+        for (filename, file) in &to.files {
+            match from.files.get(filename) {
+                None => diff.added.push(filename.clone()),
+                Some(old) if !old.same_content(file) => diff.modified.push(filename.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = from.files.keys().filter(|filename| !to.files.contains_key(*filename)).cloned().collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+// A file of an opened snapshot, with everything needed to read it
+#[derive(Debug)]
+pub(crate) struct SnapshotEntry {
+    pub(crate) cubes: Vec<Arc<Cube>>,
+    pub(crate) key: Option<DataKey>,
+}
+
+/// Reads the files of a snapshot, see [`Database::open_snapshot`].
+///
+/// The reader doesn't borrow the database, so files can keep changing while a backup is taken
+/// from it. The snapshot must not be deleted while it is being read.
+///
+/// [`Database::open_snapshot`]: crate::database::Database::open_snapshot
+#[derive(Debug)]
+pub struct SnapshotReader {
+    name: String,
+    timestamp: u64,
+    store: HiveStore,
+    files: BTreeMap<String, SnapshotEntry>,
+}
+
+impl SnapshotReader {
+    pub(crate) fn new(snapshot: &Snapshot, store: HiveStore, files: BTreeMap<String, SnapshotEntry>) -> Self {
+        SnapshotReader {
+            name: snapshot.name.clone(),
+            timestamp: snapshot.timestamp,
+            store,
+            files,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn filenames(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(String::as_str)
+    }
+
+    fn entry(&self, filename: &str) -> Result<&SnapshotEntry, DatabaseError> {
+        self.files.get(filename).ok_or(DatabaseError::FileNotFound(filename.to_string()))
+    }
+
+    pub fn retrieve_file(&self, filename: &str) -> Result<Vec<u8>, DatabaseError> {
+        let entry = self.entry(filename)?;
+        read_cubes(&self.store, entry.cubes.iter().map(Arc::as_ref), entry.key.as_ref())
+    }
+
+    pub fn open_reader(&self, filename: &str) -> Result<FileReader, DatabaseError> {
+        let entry = self.entry(filename)?;
+        let frames = entry.cubes.iter().flat_map(|cube| &cube.frames);
+        Ok(FileReader::new(self.store.clone(), entry.key.clone(), frames))
+    }
+}
@@ -0,0 +1,221 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+
+use blake2::{Blake2b, Digest};
+use generic_array::typenum::U64;
+
+use crate::chunker::Chunker;
+use crate::codec::{encode_beecell, Codec};
+use crate::crypto::{CellPosition, DataKey};
+use crate::database::{calculate_hash, read_frame_beecell, BeeCell, Frame};
+use crate::hive::HiveStore;
+
+/// Size of the buffer used when pumping data in or out of the hive.
+pub const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Cuts a stream of bytes into beecells as it arrives.
+///
+/// Each beecell is hashed and written to the hive as soon as its boundary is known, so at most
+/// one `max_size` chunk is held in memory no matter how large the file is. Every beecell is
+/// compressed with `codec` if that makes it smaller, and sealed when there is a `key`.
+pub(crate) struct BeeCellWriter {
+    store: HiveStore,
+    chunker: Chunker,
+    codec: Codec,
+    level: i32,
+    key: Option<DataKey>,
+    buffer: Vec<u8>,
+    beecells: Vec<BeeCell>,
+    // Hash of everything written so far, the same as calculate_hash over the whole content
+    content: Blake2b<U64>,
+}
+
+impl BeeCellWriter {
+    pub(crate) fn new(store: HiveStore, chunker: Chunker, codec: Codec, level: i32, key: Option<DataKey>) -> Self {
+        BeeCellWriter {
+            store,
+            chunker,
+            codec,
+            level,
+            key,
+            buffer: Vec::new(),
+            beecells: Vec::new(),
+            content: Blake2b::default(),
+        }
+    }
+
+    pub(crate) fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.content.update(data);
+        // A full window always contains a boundary, anything shorter may still grow. Chunks are
+        // cut straight from `data` where possible, only a partial window is copied aside.
+        let max_size = self.chunker.config().max_size;
+        let mut rest = data;
+        while !rest.is_empty() {
+            if self.buffer.is_empty() && rest.len() >= max_size {
+                let len = self.chunker.next_boundary(rest).unwrap_or(max_size);
+                self.store_chunk(&rest[..len])?;
+                rest = &rest[len..];
+                continue;
+            }
+            let take = (max_size - self.buffer.len()).min(rest.len());
+            self.buffer.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.buffer.len() == max_size {
+                self.cut_buffer()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hive addresses of the beecells written so far.
+    pub(crate) fn addresses(&self) -> impl Iterator<Item = &str> {
+        self.beecells.iter().map(BeeCell::address)
+    }
+
+    /// Flushes the last beecells and returns the manifests of everything written, along with the
+    /// hash of the whole content.
+    pub(crate) fn finish(mut self) -> io::Result<(Vec<BeeCell>, String)> {
+        while !self.buffer.is_empty() {
+            self.cut_buffer()?;
+        }
+        Ok((self.beecells, hex::encode(self.content.finalize())))
+    }
+
+    // Stores the first chunk of the buffer, keeping the rest for the next one
+    fn cut_buffer(&mut self) -> io::Result<()> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        let len = self.chunker.next_boundary(&buffer).unwrap_or(buffer.len());
+        let stored = self.store_chunk(&buffer[..len]);
+        buffer.drain(..len);
+        self.buffer = buffer;
+        stored
+    }
+
+    fn store_chunk(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let len = chunk.len();
+        let index = self.beecells.len();
+        let mut beecell = BeeCell::new(index, calculate_hash(chunk), len as u64);
+        let object = encode_beecell(chunk, self.codec, self.level, self.key.as_ref(), CellPosition::of(index)).map_err(io::Error::other)?;
+        match object {
+            Some(object) => {
+                beecell.object = calculate_hash(&object);
+                beecell.object_size = object.len() as u64;
+                self.store.write_beecell(&beecell.object, &object)?;
+            }
+            None => self.store.write_beecell(&beecell.hash, chunk)?,
+        }
+        self.beecells.push(beecell);
+        Ok(())
+    }
+}
+
+// Where one beecell sits inside the file, and which frame can rebuild it
+#[derive(Debug, Clone)]
+struct CellLocation {
+    offset: u64,
+    frame: Arc<Frame>,
+    cell: usize,
+}
+
+impl CellLocation {
+    fn size(&self) -> u64 {
+        self.frame.beecells[self.cell].size
+    }
+}
+
+/// Reads a stored file one beecell at a time, see [`Database::open_reader`].
+///
+/// [`Database::open_reader`]: crate::database::Database::open_reader
+#[derive(Debug)]
+pub struct FileReader {
+    store: HiveStore,
+    key: Option<DataKey>,
+    cells: Vec<CellLocation>,
+    len: u64,
+    position: u64,
+    // Index and payload of the beecell the position is currently in
+    current: Option<(usize, Vec<u8>)>,
+}
+
+impl FileReader {
+    pub(crate) fn new<'a>(store: HiveStore, key: Option<DataKey>, frames: impl IntoIterator<Item = &'a Frame>) -> Self {
+        let mut cells = Vec::new();
+        let mut offset = 0;
+        for frame in frames {
+            let frame = Arc::new(frame.clone());
+            for (cell, beecell) in frame.beecells.iter().enumerate() {
+                cells.push(CellLocation {
+                    offset,
+                    frame: frame.clone(),
+                    cell,
+                });
+                offset += beecell.size;
+            }
+        }
+
+        FileReader {
+            store,
+            key,
+            cells,
+            len: offset,
+            position: 0,
+            current: None,
+        }
+    }
+
+    /// Total size of the file in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn load_cell(&mut self, index: usize) -> io::Result<&[u8]> {
+        if self.current.as_ref().map(|(current, _)| *current) != Some(index) {
+            // Every beecell is checked against its hash before any of it is handed out
+            let location = &self.cells[index];
+            let data = read_frame_beecell(&self.store, &location.frame, location.cell, self.key.as_ref(), index)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+            self.current = Some((index, data));
+        }
+        Ok(self.current.as_ref().map(|(_, data)| data.as_slice()).unwrap_or_default())
+    }
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.len {
+            return Ok(0);
+        }
+
+        let position = self.position;
+        let index = self.cells.partition_point(|cell| cell.offset + cell.size() <= position);
+        let start = (position - self.cells[index].offset) as usize;
+        let data = self.load_cell(index)?;
+
+        let available = data.len() - start;
+        let count = available.min(buf.len());
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for FileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position")),
+        }
+    }
+}
@@ -12,6 +12,14 @@ pub struct User {
     pub authenticate: bool,
     pub key: [u8; 32],
     pub password_hash: String,
+    /// Most bytes the user may store, falling back to the hive's default quota when `None`.
+    #[serde(default)]
+    pub quota: Option<u64>,
+    /// May delete anyone's snapshots and set quotas, see [`Database::set_admin`].
+    ///
+    /// [`Database::set_admin`]: crate::database::Database::set_admin
+    #[serde(default)]
+    pub admin: bool,
 }
 
 impl User {
@@ -29,6 +37,8 @@ impl User {
             authenticate: false,
             key: [0; 32],
             password_hash: String::new(),
+            quota: None,
+            admin: false,
         })
     }
     